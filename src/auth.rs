@@ -0,0 +1,190 @@
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use reqwest::header::{HeaderMap, HeaderValue};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The shared secret of a cluster, given directly or as the first line of
+/// `token_file`. Trackers, servers and downloaders of a cluster all use the
+/// same token.
+pub fn load_token(token: Option<String>, token_file: Option<&Path>) -> io::Result<Option<String>> {
+    let token = match token_file {
+        Some(path) => Some(
+            std::fs::read_to_string(path)?
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        ),
+        None => token,
+    };
+    match token {
+        Some(token) if token.is_empty() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the token is empty",
+        )),
+        Some(token) if HeaderValue::from_str(&format!("Bearer {}", token)).is_err() => {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the token is not a valid header value",
+            ))
+        }
+        token => Ok(token),
+    }
+}
+
+/// What clients present to trackers and servers: the shared token, the CA
+/// their certificates are signed by, and a client certificate for servers
+/// that require mutual TLS.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub token: Option<String>,
+    pub ca: Option<reqwest::Certificate>,
+    pub identity: Option<reqwest::Identity>,
+}
+
+impl Credentials {
+    pub fn new(token: Option<String>) -> Self {
+        Credentials {
+            token,
+            ..Default::default()
+        }
+    }
+
+    /// Trusts the CA in the PEM file `ca`, in addition to the system roots,
+    /// and presents the PEM certificate and PKCS#8 key in `identity`.
+    pub fn with_tls(
+        mut self,
+        ca: Option<&Path>,
+        identity: Option<(&Path, &Path)>,
+    ) -> io::Result<Self> {
+        if let Some(ca) = ca {
+            let pem = std::fs::read(ca)?;
+            self.ca = Some(reqwest::Certificate::from_pem(&pem).map_err(invalid_pem)?);
+        }
+        if let Some((cert, key)) = identity {
+            let (cert, key) = (std::fs::read(cert)?, std::fs::read(key)?);
+            self.identity =
+                Some(reqwest::Identity::from_pkcs8_pem(&cert, &key).map_err(invalid_pem)?);
+        }
+        Ok(self)
+    }
+
+    /// Headers that authenticate every request with the token.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = self.token.as_ref() {
+            let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .expect("load_token only accepts tokens that are valid header values");
+            value.set_sensitive(true);
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        headers
+    }
+
+    /// A client that sends the token and certificate with every request.
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder().default_headers(self.headers());
+        if let Some(ca) = self.ca.clone() {
+            builder = builder.add_root_certificate(ca);
+        }
+        if let Some(identity) = self.identity.clone() {
+            builder = builder.identity(identity);
+        }
+        builder
+    }
+}
+
+fn invalid_pem(err: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+// compares every byte, so the time taken does not tell how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn require_token(State(token): State<Arc<String>>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes()));
+    if authorized {
+        next.run(req).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// Rejects requests to the routes added to `router` so far unless they carry
+/// `token`. Routes added afterwards stay open.
+pub fn protect<S: Clone + Send + Sync + 'static>(
+    router: Router<S>,
+    token: Option<String>,
+) -> Router<S> {
+    match token {
+        Some(token) => router.route_layer(middleware::from_fn_with_state(
+            Arc::new(token),
+            require_token,
+        )),
+        None => router,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_load_token() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("token");
+        std::fs::write(&path, "s3cret\nnot part of the token\n")?;
+        assert_eq!(load_token(None, Some(&path))?, Some("s3cret".to_string()));
+
+        std::fs::write(&path, "\n")?;
+        assert!(load_token(None, Some(&path)).is_err());
+        assert!(load_token(Some("new\rline".to_string()), None).is_err());
+        assert_eq!(load_token(None, None)?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_protect() {
+        let app = protect(
+            Router::new().route("/secret", get(|| async { "secret" })),
+            Some("s3cret".to_string()),
+        )
+        .route("/open", get(|| async { "open" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let status = |token: Option<&str>, path: &str| {
+            let client = Credentials::new(token.map(str::to_string))
+                .client_builder()
+                .build()
+                .unwrap();
+            let url = format!("{}{}", addr, path);
+            async move { client.get(url).send().await.unwrap().status() }
+        };
+        assert_eq!(status(Some("s3cret"), "/secret").await, StatusCode::OK);
+        assert_eq!(status(None, "/secret").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Some("guess"), "/secret").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(None, "/open").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,554 @@
+use crate::auth::Credentials;
+use axum::{
+    Router,
+    extract::{Json, State},
+    response::{IntoResponse, Json as ResponseJson},
+    routing::post,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Contacts kept per bucket, and nodes a content id is published to.
+const K: usize = 20;
+
+/// Nodes queried in parallel by a lookup.
+const ALPHA: usize = 3;
+
+/// How long a provider record lives unless it is published again.
+const PROVIDER_TTL: Duration = Duration::from_secs(300);
+
+/// Providers recorded per content id. A new one replaces the record that
+/// expires first.
+const MAX_PROVIDERS_PER_ID: usize = 32;
+
+/// Content ids a node records providers of. `/dht` is open to anyone, so
+/// this bounds the memory records can take.
+const MAX_PROVIDED_IDS: usize = 16 * 1024;
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Position of a node or a content id in the DHT. Nodes are identified by
+/// their URL, so the key of a node is the md5 of its URL, and the key of a
+/// content id is the md5 of the id.
+fn key_of(s: &str) -> u128 {
+    u128::from_be_bytes(md5::compute(s.as_bytes()).0)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FindNodeRequest {
+    /// URL of the sending node, unset for clients that are not DHT nodes.
+    pub from: Option<String>,
+    pub target: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FindNodeResponse {
+    pub nodes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FindProvidersRequest {
+    pub from: Option<String>,
+    pub md5: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FindProvidersResponse {
+    pub providers: Vec<String>,
+    /// Nodes closer to the id, to continue the lookup with.
+    pub nodes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddProviderRequest {
+    pub from: Option<String>,
+    pub md5: String,
+    pub provider: String,
+}
+
+/// Known nodes in buckets by the length of the prefix they share with this
+/// node. Full buckets keep their old contacts, which are the most likely to
+/// stay up.
+#[derive(Debug)]
+struct RoutingTable {
+    own: u128,
+    buckets: Vec<Vec<String>>,
+}
+
+impl RoutingTable {
+    fn new(own: u128) -> Self {
+        RoutingTable {
+            own,
+            buckets: vec![Vec::new(); 128],
+        }
+    }
+
+    fn bucket(&self, addr: &str) -> Option<usize> {
+        match key_of(addr) ^ self.own {
+            0 => None,
+            distance => Some(127 - distance.leading_zeros() as usize),
+        }
+    }
+
+    fn insert(&mut self, addr: &str) {
+        if let Some(index) = self.bucket(addr) {
+            let bucket = &mut self.buckets[index];
+            if bucket.len() < K && !bucket.iter().any(|known| known == addr) {
+                bucket.push(addr.to_string());
+            }
+        }
+    }
+
+    fn remove(&mut self, addr: &str) {
+        if let Some(index) = self.bucket(addr) {
+            self.buckets[index].retain(|known| known != addr);
+        }
+    }
+
+    fn closest(&self, key: u128, count: usize) -> Vec<String> {
+        let mut nodes = self.buckets.iter().flatten().collect::<Vec<_>>();
+        nodes.sort_by_key(|addr| key_of(addr) ^ key);
+        nodes.into_iter().take(count).cloned().collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|bucket| bucket.is_empty())
+    }
+}
+
+/// A node of the DHT, or a client that only looks up providers.
+pub struct Dht {
+    own_addr: Option<String>,
+    bootstrap: Vec<String>,
+    table: Mutex<RoutingTable>,
+    // content id -> provider -> expiry
+    providers: Mutex<HashMap<String, HashMap<String, Instant>>>,
+    client: reqwest::Client,
+}
+
+impl Dht {
+    /// A node reachable at `own_addr` that joins through `bootstrap`.
+    pub fn node(own_addr: String, bootstrap: Vec<String>) -> Self {
+        Self::new(Some(own_addr), bootstrap)
+    }
+
+    /// A client that is not reachable and is never added to routing tables.
+    pub fn client(bootstrap: Vec<String>) -> Self {
+        Self::new(None, bootstrap)
+    }
+
+    fn new(own_addr: Option<String>, bootstrap: Vec<String>) -> Self {
+        let own = own_addr.as_deref().map_or(0, key_of);
+        Dht {
+            own_addr,
+            bootstrap,
+            table: Mutex::new(RoutingTable::new(own)),
+            providers: Mutex::new(HashMap::new()),
+            client: reqwest::Client::builder()
+                .timeout(RPC_TIMEOUT)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    /// Presents the certificates of `credentials` to other nodes. The token is
+    /// never sent: the DHT routes are open, and any node can join the DHT.
+    pub fn with_credentials(mut self, credentials: &Credentials) -> Self {
+        let credentials = Credentials {
+            token: None,
+            ..credentials.clone()
+        };
+        self.client = credentials
+            .client_builder()
+            .timeout(RPC_TIMEOUT)
+            .build()
+            .unwrap();
+        self
+    }
+
+    fn learn(&self, addr: &str) {
+        if self.own_addr.as_deref() != Some(addr) {
+            self.table.lock().unwrap().insert(addr);
+        }
+    }
+
+    fn forget(&self, addr: &str) {
+        self.table.lock().unwrap().remove(addr);
+    }
+
+    async fn rpc<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        addr: &str,
+        path: &str,
+        req: &Req,
+    ) -> reqwest::Result<Resp> {
+        let result = async {
+            self.client
+                .post(format!("{}/dht/{}", addr, path))
+                .json(req)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Resp>()
+                .await
+        }
+        .await;
+        match result.as_ref() {
+            Ok(_) => self.learn(addr),
+            Err(_) => self.forget(addr),
+        }
+        result
+    }
+
+    /// Fills the routing table from the bootstrap nodes by looking up the
+    /// nodes closest to this one.
+    pub async fn join(&self) {
+        for addr in self.bootstrap.iter() {
+            self.learn(addr);
+        }
+        let own = self.own_addr.clone().unwrap_or_default();
+        self.lookup(own.as_str(), false).await;
+    }
+
+    /// Iteratively queries the nodes closest to `target`. Returns the closest
+    /// nodes that answered and, when `providers` is set, stops as soon as a
+    /// node knows providers of `target` and returns them too.
+    async fn lookup(&self, target: &str, providers: bool) -> (Vec<String>, Vec<String>) {
+        let key = key_of(target);
+        let mut shortlist = BTreeMap::new();
+        for addr in self.table.lock().unwrap().closest(key, K) {
+            shortlist.insert(key_of(&addr) ^ key, addr);
+        }
+        let mut queried = HashSet::new();
+        let mut answered = BTreeMap::new();
+        let mut found = Vec::new();
+
+        loop {
+            let batch = shortlist
+                .values()
+                .take(K)
+                .filter(|addr| !queried.contains(*addr))
+                .take(ALPHA)
+                .cloned()
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().cloned());
+
+            let responses = futures::future::join_all(batch.iter().map(|addr| async move {
+                if providers {
+                    let req = FindProvidersRequest {
+                        from: self.own_addr.clone(),
+                        md5: target.to_string(),
+                    };
+                    self.rpc::<_, FindProvidersResponse>(addr, "find_providers", &req)
+                        .await
+                } else {
+                    let req = FindNodeRequest {
+                        from: self.own_addr.clone(),
+                        target: target.to_string(),
+                    };
+                    self.rpc::<_, FindNodeResponse>(addr, "find_node", &req)
+                        .await
+                        .map(|resp| FindProvidersResponse {
+                            providers: Vec::new(),
+                            nodes: resp.nodes,
+                        })
+                }
+            }))
+            .await;
+
+            for (addr, response) in batch.into_iter().zip(responses) {
+                let distance = key_of(&addr) ^ key;
+                match response {
+                    Ok(resp) => {
+                        answered.insert(distance, addr);
+                        found.extend(resp.providers);
+                        for node in resp.nodes {
+                            if self.own_addr.as_ref() != Some(&node) {
+                                shortlist.insert(key_of(&node) ^ key, node);
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        shortlist.remove(&distance);
+                    }
+                }
+            }
+            if providers && !found.is_empty() {
+                break;
+            }
+        }
+
+        found.sort();
+        found.dedup();
+        (answered.into_values().take(K).collect(), found)
+    }
+
+    /// Records `provider` for `md5` within the limits, dropping expired
+    /// records first. Returns whether it was recorded.
+    fn store_provider(&self, md5: &str, provider: &str) -> bool {
+        let mut providers = self.providers.lock().unwrap();
+        let now = Instant::now();
+        if !providers.contains_key(md5) && providers.len() >= MAX_PROVIDED_IDS {
+            prune_providers(&mut providers, now);
+            if providers.len() >= MAX_PROVIDED_IDS {
+                return false;
+            }
+        }
+        let by_addr = providers.entry(md5.to_string()).or_default();
+        by_addr.retain(|_, expiry| *expiry > now);
+        if !by_addr.contains_key(provider)
+            && by_addr.len() >= MAX_PROVIDERS_PER_ID
+            && let Some(first) = by_addr
+                .iter()
+                .min_by_key(|(_, expiry)| **expiry)
+                .map(|(addr, _)| addr.clone())
+        {
+            by_addr.remove(&first);
+        }
+        by_addr.insert(provider.to_string(), now + PROVIDER_TTL);
+        true
+    }
+
+    fn local_providers(&self, md5: &str) -> Vec<String> {
+        let mut providers = self.providers.lock().unwrap();
+        prune_providers(&mut providers, Instant::now());
+        providers
+            .get(md5)
+            .map(|by_addr| by_addr.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records this node as a provider of every id in `md5s` on the nodes
+    /// closest to each id.
+    pub async fn publish(&self, md5s: &[String]) {
+        let Some(own_addr) = self.own_addr.as_ref() else {
+            return;
+        };
+        if self.table.lock().unwrap().is_empty() {
+            // every known node left, start over from the bootstrap nodes
+            self.join().await;
+        }
+        for md5 in md5s.iter() {
+            self.store_provider(md5, own_addr);
+            let (closest, _) = self.lookup(md5, false).await;
+            let req = AddProviderRequest {
+                from: Some(own_addr.clone()),
+                md5: md5.clone(),
+                provider: own_addr.clone(),
+            };
+            futures::future::join_all(
+                closest
+                    .iter()
+                    .map(|addr| self.rpc::<_, ()>(addr, "add_provider", &req)),
+            )
+            .await;
+        }
+    }
+
+    /// Looks up the nodes that published `md5`.
+    pub async fn find_providers(&self, md5: &str) -> Vec<String> {
+        let local = self.local_providers(md5);
+        if !local.is_empty() {
+            return local;
+        }
+        self.lookup(md5, true).await.1
+    }
+
+    /// Publishes the ids in `md5s` every `interval`, and right away when they
+    /// change, so provider records never expire while this node is up.
+    pub fn spawn_publisher(
+        self: Arc<Self>,
+        mut md5s: watch::Receiver<Vec<String>>,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            self.join().await;
+            loop {
+                let current = md5s.borrow_and_update().clone();
+                self.publish(&current).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    Ok(()) = md5s.changed() => {}
+                }
+            }
+        })
+    }
+
+    /// The `/dht/...` routes other nodes send their requests to.
+    pub fn router(self: Arc<Self>) -> Router {
+        Router::new()
+            .route("/dht/find_node", post(handle_find_node))
+            .route("/dht/find_providers", post(handle_find_providers))
+            .route("/dht/add_provider", post(handle_add_provider))
+            .with_state(self)
+    }
+}
+
+/// Drops the provider records that expired by `now`.
+fn prune_providers(providers: &mut HashMap<String, HashMap<String, Instant>>, now: Instant) {
+    providers.retain(|_, by_addr| {
+        by_addr.retain(|_, expiry| *expiry > now);
+        !by_addr.is_empty()
+    });
+}
+
+async fn handle_find_node(
+    State(dht): State<Arc<Dht>>,
+    Json(req): Json<FindNodeRequest>,
+) -> impl IntoResponse {
+    let nodes = dht.table.lock().unwrap().closest(key_of(&req.target), K);
+    if let Some(from) = req.from.as_ref() {
+        dht.learn(from);
+    }
+    ResponseJson(FindNodeResponse { nodes })
+}
+
+async fn handle_find_providers(
+    State(dht): State<Arc<Dht>>,
+    Json(req): Json<FindProvidersRequest>,
+) -> impl IntoResponse {
+    let providers = dht.local_providers(&req.md5);
+    let nodes = dht.table.lock().unwrap().closest(key_of(&req.md5), K);
+    if let Some(from) = req.from.as_ref() {
+        dht.learn(from);
+    }
+    ResponseJson(FindProvidersResponse { providers, nodes })
+}
+
+async fn handle_add_provider(
+    State(dht): State<Arc<Dht>>,
+    Json(req): Json<AddProviderRequest>,
+) -> impl IntoResponse {
+    // nodes only publish themselves, anything else would let one node fill
+    // the records with any number of addresses
+    if req.from.as_ref() != Some(&req.provider) {
+        return ResponseJson(());
+    }
+    dht.store_provider(&req.md5, &req.provider);
+    if let Some(from) = req.from.as_ref() {
+        dht.learn(from);
+    }
+    ResponseJson(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn start_node(bootstrap: Vec<String>) -> (Arc<Dht>, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let dht = Arc::new(Dht::node(addr, bootstrap));
+        let app = dht.clone().router();
+        let handle = tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (dht, handle)
+    }
+
+    #[test]
+    fn test_routing_table() {
+        let mut table = RoutingTable::new(key_of("self"));
+        table.insert("self");
+        assert!(table.is_empty());
+        for i in 0..100 {
+            table.insert(&format!("node{}", i));
+        }
+        // the top bucket holds about half of all nodes, but no more than K
+        assert_eq!(table.buckets[127].len(), K);
+
+        let key = key_of("x");
+        let closest = table.closest(key, 5);
+        assert_eq!(closest.len(), 5);
+        assert!(
+            closest
+                .windows(2)
+                .all(|w| (key_of(&w[0]) ^ key) <= (key_of(&w[1]) ^ key))
+        );
+        table.remove(&closest[0]);
+        assert!(!table.closest(key, 5).contains(&closest[0]));
+    }
+
+    #[tokio::test]
+    async fn test_provider_limits() {
+        let dht = Arc::new(Dht::node("http://self".to_string(), Vec::new()));
+        let add = |from: Option<&str>, md5: &str, provider: &str| {
+            handle_add_provider(
+                State(dht.clone()),
+                Json(AddProviderRequest {
+                    from: from.map(str::to_string),
+                    md5: md5.to_string(),
+                    provider: provider.to_string(),
+                }),
+            )
+        };
+
+        // nodes can only publish themselves
+        add(Some("http://a"), "x", "http://b").await;
+        add(None, "x", "http://b").await;
+        assert!(dht.local_providers("x").is_empty());
+        add(Some("http://a"), "x", "http://a").await;
+        assert_eq!(dht.local_providers("x"), vec!["http://a"]);
+
+        // a full id replaces the record that expires first
+        for i in 0..MAX_PROVIDERS_PER_ID {
+            assert!(dht.store_provider("x", &format!("http://p{}", i)));
+        }
+        let providers = dht.local_providers("x");
+        assert_eq!(providers.len(), MAX_PROVIDERS_PER_ID);
+        assert!(!providers.contains(&"http://a".to_string()));
+
+        // expired records make room when the node is full
+        for i in 1..MAX_PROVIDED_IDS {
+            assert!(dht.store_provider(&format!("id{}", i), "http://a"));
+        }
+        assert!(!dht.store_provider("y", "http://a"));
+        let expired = Instant::now() - Duration::from_millis(1);
+        for by_addr in dht.providers.lock().unwrap().values_mut() {
+            by_addr.values_mut().for_each(|expiry| *expiry = expired);
+        }
+        assert!(dht.store_provider("y", "http://a"));
+        assert_eq!(dht.providers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_find_providers() {
+        let (first, first_handle) = start_node(Vec::new()).await;
+        let bootstrap = vec![first.own_addr.clone().unwrap()];
+        let mut nodes = vec![first];
+        let mut handles = Vec::new();
+        for _ in 0..29 {
+            let (node, handle) = start_node(bootstrap.clone()).await;
+            node.join().await;
+            nodes.push(node);
+            handles.push(handle);
+        }
+
+        let publisher = &nodes[17];
+        publisher.publish(&["x".to_string()]).await;
+        let expected = vec![publisher.own_addr.clone().unwrap()];
+
+        // any node, and a client that only knows one node, finds the provider
+        assert_eq!(nodes[5].find_providers("x").await, expected);
+        let client = Dht::client(vec![nodes[23].own_addr.clone().unwrap()]);
+        client.join().await;
+        assert_eq!(client.find_providers("x").await, expected);
+        assert!(client.find_providers("y").await.is_empty());
+
+        // the record survives when the bootstrap node and a few others leave
+        for handle in handles.iter().take(5) {
+            handle.abort();
+        }
+        first_handle.abort();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = Dht::client(vec![nodes[29].own_addr.clone().unwrap()]);
+        client.join().await;
+        assert_eq!(client.find_providers("x").await, expected);
+    }
+}
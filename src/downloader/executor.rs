@@ -1,7 +1,15 @@
-use tokio::{fs, io::AsyncWriteExt};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
 
+use crate::auth::Credentials;
+use crate::downloader::partial::PartialDownload;
 use crate::downloader::planer::Action;
-use reqwest::Client;
+use crate::downloader::swarm::{PIECE_SIZE, SWARM_THRESHOLD, swarm_download};
+use crate::hash::{HashAlgo, Hasher};
+use reqwest::{Client, StatusCode, header};
+use std::io::SeekFrom;
 use std::sync::{Arc, Mutex};
 use std::{collections::VecDeque, io, path::Path, time::Duration};
 
@@ -26,16 +34,50 @@ impl<'a> Iterator for DownloadURLs<'a> {
     }
 }
 
+/// Feeds the first `len` bytes of the file at `path` into `ctx`.
+async fn consume_prefix(ctx: &mut Hasher, path: &Path, len: u64) -> io::Result<()> {
+    let mut reader = fs::File::open(path).await?.take(len);
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        ctx.update(&buffer[..n]);
+    }
+}
+
 async fn download_and_check(
     client: &Client,
     url: &str,
     md5: &str,
+    size: usize,
     file_path: &Path,
     pbar: Arc<Mutex<tqdm::Tqdm<()>>>,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
-    let mut resp = client.get(url).send().await?;
-    let mut output_file = fs::File::create(file_path).await?;
-    let mut md5_context = md5::Context::new();
+    let (mut partial, output_file) = PartialDownload::open(file_path, md5, size as u64)?;
+    let mut output_file = fs::File::from_std(output_file);
+    let algo = HashAlgo::of_id(md5)?;
+    let mut md5_context = algo.hasher();
+
+    // resume after the bytes an earlier attempt already wrote
+    let mut offset = partial.prefix();
+    let mut req = client.get(url);
+    if offset > 0 {
+        req = req
+            .header(header::RANGE, format!("bytes={}-", offset))
+            .header(header::IF_RANGE, format!("\"{}\"", md5));
+    }
+    let mut resp = req.send().await?.error_for_status()?;
+    if offset > 0 && resp.status() == StatusCode::PARTIAL_CONTENT {
+        consume_prefix(&mut md5_context, partial.part_path(), offset).await?;
+        pbar.lock().unwrap().update(offset as usize)?;
+    } else {
+        partial.reset()?;
+        offset = 0;
+    }
+    output_file.set_len(offset).await?;
+    output_file.seek(SeekFrom::Start(offset)).await?;
 
     // Use larger buffer for better performance
     const BUFFER_SIZE: usize = 4 * 1024 * 1024; // 4MB buffer
@@ -44,12 +86,15 @@ async fn download_and_check(
     while let Some(chunk) = resp.chunk().await? {
         // Add chunk to buffer
         buffer.extend_from_slice(&chunk);
-        md5_context.consume(&chunk);
+        md5_context.update(&chunk);
 
         pbar.lock().unwrap().update(chunk.len())?;
         // Write buffer to file when it's large enough
         if buffer.len() >= BUFFER_SIZE {
             output_file.write_all(&buffer).await?;
+            output_file.sync_data().await?;
+            offset += buffer.len() as u64;
+            partial.mark_done(0, offset)?;
             buffer.clear();
         }
     }
@@ -61,11 +106,13 @@ async fn download_and_check(
 
     // Ensure all data is written to disk
     output_file.flush().await?;
+    output_file.sync_all().await?;
 
-    if md5 != format!("{:x}", md5_context.compute()) {
-        Err("md5 mismatch".into())
+    if md5 != md5_context.finish() {
+        partial.discard()?;
+        Err(format!("{} mismatch", algo).into())
     } else {
-        Ok(())
+        Ok(partial.finish()?)
     }
 }
 
@@ -74,13 +121,13 @@ async fn execute_action(
     pbar: Arc<Mutex<tqdm::Tqdm<()>>>,
     client: Arc<Client>,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
-    Ok(match action {
+    match action {
         Action::Download {
             peers,
             peer_id,
             path: file_path,
             md5,
-            size: _,
+            size,
         } => {
             if let Some(parent) = file_path.parent() {
                 fs::create_dir_all(parent).await?;
@@ -90,10 +137,27 @@ async fn execute_action(
             let peers_vec = {
                 let peers_read_guard = peers
                     .read()
-                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+                    .map_err(|err| io::Error::other(err.to_string()))?;
                 peers_read_guard.clone()
             };
 
+            if size >= SWARM_THRESHOLD && peers_vec.len() > 1 {
+                // start at this file's peer, so the first pieces spread over peers
+                let mut peers_vec = peers_vec;
+                let first = peer_id % peers_vec.len();
+                peers_vec.rotate_left(first);
+                return swarm_download(
+                    &client,
+                    &md5,
+                    &peers_vec,
+                    file_path.as_path(),
+                    size as u64,
+                    PIECE_SIZE,
+                    pbar,
+                )
+                .await;
+            }
+
             let urls = DownloadURLs {
                 md5: &md5,
                 peers: &peers_vec,
@@ -107,6 +171,7 @@ async fn execute_action(
                     &client,
                     url.as_str(),
                     &md5,
+                    size,
                     file_path.as_path(),
                     pbar.clone(),
                 )
@@ -120,18 +185,19 @@ async fn execute_action(
             }
 
             let error_msgs: Vec<String> = errs.iter().map(|e| e.to_string()).collect();
-            return Err(format!("All download attempts failed: {}", error_msgs.join("; ")).into());
+            Err(format!("All download attempts failed: {}", error_msgs.join("; ")).into())
         }
-        Action::MakeDir { path } => fs::create_dir_all(path).await?,
-    })
+        Action::MakeDir { path } => Ok(fs::create_dir_all(path).await?),
+        Action::Remove { path } => Ok(fs::remove_file(path).await?),
+        Action::RemoveDir { path } => Ok(fs::remove_dir_all(path).await?),
+    }
 }
 
-fn total_size(actions: &Vec<Action>) -> usize {
+pub fn total_size(actions: &Vec<Action>) -> usize {
     let mut total_size = 0;
     for action in actions {
-        match action {
-            Action::Download { size, .. } => total_size += size,
-            Action::MakeDir { .. } => {}
+        if let Action::Download { size, .. } = action {
+            total_size += size;
         }
     }
     total_size
@@ -140,9 +206,11 @@ fn total_size(actions: &Vec<Action>) -> usize {
 pub async fn execute_actions(
     actions: &Vec<Action>,
     concurrency: usize,
+    credentials: &Credentials,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Create optimized reqwest client with larger buffers and better performance settings
-    let client = Client::builder()
+    let client = credentials
+        .client_builder()
         .timeout(Duration::from_secs(120))
         .connect_timeout(Duration::from_secs(10))
         .pool_max_idle_per_host(20)
@@ -171,8 +239,10 @@ pub async fn execute_actions(
 
         if handles.len() > concurrency {
             let handle = handles.pop_front().unwrap();
-            if let Err(err) = handle.await {
-                errs.push(err);
+            match handle.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => errs.push(err),
+                Err(err) => errs.push(err.into()),
             }
         }
 
@@ -182,8 +252,10 @@ pub async fn execute_actions(
     }
 
     while let Some(handle) = handles.pop_front() {
-        if let Err(err) = handle.await {
-            errs.push(err);
+        match handle.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => errs.push(err),
+            Err(err) => errs.push(err.into()),
         }
     }
 
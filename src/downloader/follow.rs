@@ -0,0 +1,204 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::stage::Versions;
+use super::{DownloadOptions, download};
+use crate::tracker;
+
+#[derive(Debug, Clone)]
+pub struct FollowOptions {
+    pub tag: String,
+    /// Symlink to the current version, replaced whenever the tag moves.
+    pub dest: PathBuf,
+    /// How often the trackers are asked where the tag points.
+    pub interval: Duration,
+    /// How each version is downloaded. `dest` and `versioned` are set for
+    /// every version, `seed` is not used.
+    pub download: DownloadOptions,
+}
+
+/// Keeps `dest` at the version `tag` points to, checking the trackers every
+/// `interval`. Each version is downloaded next to the current one, which
+/// stays in place until the new one is verified. Failures are reported and
+/// retried at the next check. Only returns on errors with the setup.
+pub async fn follow(
+    options: FollowOptions,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let versions = Versions::of(&options.dest)?;
+    // fail right away when dest is not a symlink this follows
+    versions.current()?;
+    let client = options.download.credentials.client_builder().build()?;
+    let mut download_options = options.download.clone();
+    download_options.dest = Some(options.dest.clone());
+    download_options.versioned = true;
+    download_options.dry_run = false;
+    download_options.seed = None;
+    loop {
+        match tracker::resolve_tag(&client, &options.download.tracker_urls, &options.tag).await {
+            // peers are only asked for the tree when the tag moved
+            Ok(tag) if versions.current()?.as_deref() != Some(tag.md5.as_str()) => {
+                match download(tag.md5.clone(), download_options.clone()).await {
+                    Ok(()) => eprintln!(
+                        "{} switched to {} ({})",
+                        options.dest.display(),
+                        tag.md5,
+                        options.tag
+                    ),
+                    Err(err) => eprintln!(
+                        "Failed to sync {} to {}: {}",
+                        options.dest.display(),
+                        tag.md5,
+                        err
+                    ),
+                }
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("{}", err),
+        }
+        tokio::time::sleep(options.interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::LookupDirOrFile;
+    use crate::tracker::{PeerInfo, PeersResponse, TagResponse};
+    use axum::extract::Query;
+    use axum::{Json, Router, routing::get};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    fn id(data: &str) -> String {
+        format!("{:x}", md5::compute(data))
+    }
+
+    /// The tree of a version with a single file holding `data`.
+    fn tree(data: &str) -> LookupDirOrFile {
+        LookupDirOrFile::Dir {
+            name: "model".to_string(),
+            children: vec![LookupDirOrFile::File {
+                name: "weights.bin".to_string(),
+                md5: id(data),
+                size: data.len(),
+            }],
+        }
+    }
+
+    async fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    /// A peer serving the versions "v1" and "v2".
+    async fn start_peer() -> String {
+        let app = Router::new()
+            .route(
+                "/query",
+                get(|Query(params): Query<HashMap<String, String>>| async move {
+                    let md5 = &params["md5"];
+                    let version = ["v1", "v2"].into_iter().find(|v| id(v) == *md5);
+                    Json(tree(&format!("{}\n", version.unwrap())))
+                }),
+            )
+            .route(
+                "/download",
+                get(|Query(params): Query<HashMap<String, String>>| async move {
+                    let md5 = &params["md5"];
+                    let data = ["v1\n", "v2\n"].into_iter().find(|d| id(d) == *md5);
+                    data.unwrap()
+                }),
+            );
+        serve(app).await
+    }
+
+    /// A tracker listing `peer` whose tag "model:latest" points to `tag`.
+    async fn start_tracker(peer: String, tag: Arc<Mutex<String>>) -> String {
+        let app = Router::new()
+            .route(
+                "/peers",
+                get(move || {
+                    let peer = peer.clone();
+                    async move {
+                        Json(PeersResponse {
+                            peers: vec![PeerInfo {
+                                addr: peer,
+                                last_seen: 1,
+                                unconfirmed: false,
+                                unhealthy: false,
+                            }],
+                        })
+                    }
+                }),
+            )
+            .route(
+                "/tags/model:latest",
+                get(move || {
+                    let md5 = tag.lock().unwrap().clone();
+                    async move {
+                        Json(TagResponse {
+                            name: "model:latest".to_string(),
+                            md5,
+                            published_at: 1,
+                        })
+                    }
+                }),
+            );
+        serve(app).await
+    }
+
+    async fn wait_for(path: &std::path::Path, data: &str) {
+        for _ in 0..100 {
+            if std::fs::read_to_string(path).is_ok_and(|read| read == data) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{} never held {:?}", path.display(), data);
+    }
+
+    #[tokio::test]
+    async fn test_follow_switches_when_the_tag_moves() {
+        let tag = Arc::new(Mutex::new(id("v1")));
+        let tracker = start_tracker(start_peer().await, tag.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("model");
+        let options = FollowOptions {
+            tag: "model:latest".to_string(),
+            dest: dest.clone(),
+            interval: Duration::from_millis(50),
+            download: DownloadOptions {
+                concurrency: 1,
+                tracker_urls: vec![tracker],
+                dht_bootstrap: vec![],
+                lan_window: None,
+                hash_cache: None,
+                dest: None,
+                versioned: true,
+                delete: true,
+                dry_run: false,
+                trusted_peer: None,
+                trusted_key: None,
+                strict: false,
+                credentials: Default::default(),
+                seed: None,
+            },
+        };
+        let following = tokio::spawn(follow(options));
+
+        let weights = dest.join("weights.bin");
+        wait_for(&weights, "v1\n").await;
+        assert!(std::fs::symlink_metadata(&dest).unwrap().is_symlink());
+        // a reader holding on to the first version
+        let old = std::fs::canonicalize(&dest).unwrap().join("weights.bin");
+
+        *tag.lock().unwrap() = id("v2");
+        wait_for(&weights, "v2\n").await;
+        assert_eq!(std::fs::read_to_string(&old).unwrap(), "v1\n");
+        assert!(!following.is_finished());
+        following.abort();
+    }
+}
@@ -1,12 +1,455 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::auth::Credentials;
+use crate::hash::HashAlgo;
+use crate::manifest::PublicKey;
+use crate::server::{FileStamp, HashCache, ServeOptions};
+
 mod executor;
+mod follow;
+mod partial;
 mod planer;
+mod seed;
+mod stage;
+mod swarm;
+
+pub use follow::{FollowOptions, follow};
+pub use planer::UnsafeTree;
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub concurrency: usize,
+    pub tracker_urls: Vec<String>,
+    /// DHT nodes to join to look up providers, in addition to the trackers.
+    pub dht_bootstrap: Vec<String>,
+    /// Listens this long for servers announcing themselves on the local
+    /// network, in addition to the trackers.
+    pub lan_window: Option<Duration>,
+    /// Caches the md5 of local files between runs, so files that are already
+    /// present are not hashed again to find out whether they can be reused.
+    pub hash_cache: Option<PathBuf>,
+    /// Writes the root of the tree to this path instead of `./{name}`.
+    pub dest: Option<PathBuf>,
+    /// Keeps every version in `.{name}.p2psync/versions` next to the
+    /// destination, which becomes a symlink to the current one. Implied when
+    /// the destination already is such a symlink.
+    pub versioned: bool,
+    /// Removes local files and directories that are not in the remote tree.
+    /// Without it, they are carried over to the new tree.
+    pub delete: bool,
+    /// Prints what would be downloaded and removed without touching anything.
+    pub dry_run: bool,
+    /// Peer whose tree is trusted over the one most peers agree on.
+    pub trusted_peer: Option<String>,
+    /// Refuses any tree that is not in a manifest signed by this key.
+    pub trusted_key: Option<PublicKey>,
+    /// Fails instead of excluding peers that disagree on the tree.
+    pub strict: bool,
+    /// Token and certificates presented to the trackers and peers.
+    pub credentials: Credentials,
+    /// Once the download is verified, serves the tree from the server running
+    /// at the address of these options, or starts one with them, which runs
+    /// until it is shut down.
+    pub seed: Option<ServeOptions>,
+}
 
+/// Downloads the tree of `md5`. The tree is staged next to its destination,
+/// starting as hard links to the files already there so only changed files
+/// are fetched, and replaces the destination in a single rename once every
+/// file is verified. A failed download leaves the destination untouched and
+/// is resumed by the next one.
 pub async fn download(
     md5: String,
-    concurrency: usize,
-    tracker_urls: Vec<String>,
+    options: DownloadOptions,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
-    let planer = planer::Planer::new(tracker_urls);
+    let planer = planer::Planer::new(options.tracker_urls.clone())
+        .with_dht(options.dht_bootstrap.clone())
+        .with_lan(options.lan_window)
+        .with_trusted_peer(options.trusted_peer.clone())
+        .with_trusted_key(options.trusted_key.clone())
+        .with_strict(options.strict)
+        .with_dest(options.dest.clone())
+        .with_credentials(&options.credentials);
     let actions = planer.plan(md5.as_str()).await?;
-    executor::execute_actions(&actions, concurrency).await
+    let root = match actions.first() {
+        Some(planer::Action::MakeDir { path } | planer::Action::Download { path, .. }) => {
+            path.clone()
+        }
+        _ => return Ok(()),
+    };
+    let is_symlink = std::fs::symlink_metadata(&root).is_ok_and(|meta| meta.is_symlink());
+    if options.dry_run {
+        // compared with the version the symlink points to
+        let actions = if is_symlink {
+            stage::rebase(actions, &root, &std::fs::canonicalize(&root)?)
+        } else {
+            actions
+        };
+        return execute_plan(actions, &options).await;
+    }
+
+    let files = actions
+        .iter()
+        .filter_map(|action| match action {
+            planer::Action::Download { path, md5, .. } => Some((path.clone(), md5.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if options.versioned || is_symlink {
+        download_version(&md5, actions, &root, &options).await?;
+    } else {
+        let staging = stage::staging_path(&root)?;
+        stage::prepare(&staging, Some(&root), &actions[0])?;
+        execute_plan(stage::rebase(actions, &root, &staging), &options).await?;
+        stage::replace(&staging, &root)?;
+    }
+
+    if let Some(serve) = options.seed {
+        // every file was verified against its id, none is hashed again
+        let files = files
+            .into_iter()
+            .map(|(path, md5)| Ok((FileStamp::from_path(&path)?, md5)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        seed::seed(&root, &md5, files, serve).await?;
+    }
+    Ok(())
+}
+
+/// Downloads `md5` into a version next to `root` and points `root` at it.
+async fn download_version(
+    md5: &str,
+    actions: Vec<planer::Action>,
+    root: &Path,
+    options: &DownloadOptions,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let versions = stage::Versions::of(root)?;
+    versions.create_dir()?;
+    let current = versions.current()?;
+    if current.as_deref() == Some(md5) {
+        eprintln!("{} is already at {}", root.display(), md5);
+        return Ok(());
+    }
+    // the id names a directory, it must not be a path
+    HashAlgo::of_id(md5)?;
+    let version = versions.version(md5);
+    if !version.exists() {
+        let staging = versions.staging(md5);
+        let current_version = current.as_ref().map(|current| versions.version(current));
+        stage::prepare(&staging, current_version.as_deref(), &actions[0])?;
+        let mut options = options.clone();
+        // a version is exactly the tree, nothing is carried over
+        options.delete = true;
+        if options.hash_cache.is_none() {
+            options.hash_cache = Some(versions.hash_cache.clone());
+        }
+        execute_plan(stage::rebase(actions, root, &staging), &options).await?;
+        std::fs::rename(&staging, &version)?;
+    }
+    versions.switch(md5)?;
+    let mut keep = vec![md5];
+    keep.extend(current.as_deref());
+    versions.prune(&keep)?;
+    Ok(())
+}
+
+/// Reuses the files of `actions` already present, removes what `--delete`
+/// removes, and downloads the rest in place.
+async fn execute_plan(
+    actions: Vec<planer::Action>,
+    options: &DownloadOptions,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let mut cache = match options.hash_cache.as_ref() {
+        Some(path) => HashCache::load(path)?,
+        None => HashCache::new(),
+    };
+    let delete = options.delete;
+    let (actions, removals, reused, mut cache) = tokio::task::spawn_blocking(move || {
+        // removals are planned against the full tree, present files are kept
+        let removals = if delete {
+            planer::plan_removals(&actions)?
+        } else {
+            Vec::new()
+        };
+        planer::skip_present(actions, &mut cache)
+            .map(|(actions, reused)| (actions, removals, reused, cache))
+    })
+    .await??;
+
+    for action in removals.iter() {
+        if let planer::Action::Remove { path } | planer::Action::RemoveDir { path } = action {
+            eprintln!("delete {}", path.display());
+        }
+    }
+    eprintln!(
+        "{} files ({} bytes) already present, {} files ({} bytes) to download",
+        reused.files,
+        reused.bytes,
+        actions
+            .iter()
+            .filter(|action| matches!(action, planer::Action::Download { .. }))
+            .count(),
+        executor::total_size(&actions),
+    );
+
+    if options.dry_run {
+        for action in actions.iter() {
+            if let planer::Action::Download { path, size, .. } = action {
+                eprintln!("download {} ({} bytes)", path.display(), size);
+            }
+        }
+        return Ok(());
+    }
+
+    // removals go first, a planned directory may replace a local file
+    if !removals.is_empty() {
+        executor::execute_actions(&removals, options.concurrency, &options.credentials).await?;
+    }
+    executor::execute_actions(&actions, options.concurrency, &options.credentials).await?;
+
+    if let Some(path) = options.hash_cache.as_ref() {
+        // every downloaded file was verified, remember its md5
+        for action in actions.iter() {
+            if let planer::Action::Download { path, md5, .. } = action {
+                cache.insert(FileStamp::from_path(path)?, md5.clone());
+            }
+        }
+        cache.save(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::LookupDirOrFile;
+    use crate::tracker::{PeerInfo, PeersResponse};
+    use axum::body::{Body, Bytes};
+    use axum::extract::Query;
+    use axum::{Json, Router, routing::get};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    const ROOT: &str = "0123456789abcdef0123456789abcdef";
+    const FILES: [(&str, &str); 2] = [("a.txt", "new a\n"), ("b.txt", "new b\n")];
+
+    fn id(data: &str) -> String {
+        format!("{:x}", md5::compute(data))
+    }
+
+    async fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    /// A peer answering `/query` with a tree "model" holding `FILES`.
+    fn peer_app() -> Router {
+        Router::new().route(
+            "/query",
+            get(|| async {
+                let children = FILES
+                    .iter()
+                    .map(|(name, data)| LookupDirOrFile::File {
+                        name: name.to_string(),
+                        md5: id(data),
+                        size: data.len(),
+                    })
+                    .collect();
+                Json(LookupDirOrFile::Dir {
+                    name: "model".to_string(),
+                    children,
+                })
+            }),
+        )
+    }
+
+    /// The file of `FILES` with id `md5`.
+    fn file(md5: &str) -> (&'static str, &'static str) {
+        *FILES.iter().find(|(_, data)| id(data) == md5).unwrap()
+    }
+
+    /// A tracker listing `peer` for every id.
+    async fn start_tracker(peer: String) -> String {
+        let app = Router::new().route(
+            "/peers",
+            get(move || {
+                let peer = peer.clone();
+                async move {
+                    Json(PeersResponse {
+                        peers: vec![PeerInfo {
+                            addr: peer,
+                            last_seen: 1,
+                            unconfirmed: false,
+                            unhealthy: false,
+                        }],
+                    })
+                }
+            }),
+        );
+        serve(app).await
+    }
+
+    fn options(tracker: String, dest: &Path) -> DownloadOptions {
+        DownloadOptions {
+            concurrency: 2,
+            tracker_urls: vec![tracker],
+            dht_bootstrap: vec![],
+            lan_window: None,
+            hash_cache: None,
+            dest: Some(dest.to_path_buf()),
+            versioned: false,
+            delete: false,
+            dry_run: false,
+            trusted_peer: None,
+            trusted_key: None,
+            strict: false,
+            credentials: Credentials::default(),
+            seed: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_from_staging() {
+        // the peer breaks off the first transfer of b.txt midway
+        let broken = Arc::new(AtomicBool::new(true));
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let (serving, recorded) = (broken.clone(), fetched.clone());
+        let app = peer_app().route(
+            "/download",
+            get(
+                move |Query(params): Query<HashMap<String, String>>| async move {
+                    let (name, data) = file(&params["md5"]);
+                    recorded.lock().unwrap().push(name);
+                    if name == "b.txt" && serving.swap(false, Ordering::SeqCst) {
+                        let chunks: Vec<Result<Bytes, std::io::Error>> = vec![
+                            Ok(Bytes::from_static(b"new")),
+                            Err(std::io::Error::other("peer went away")),
+                        ];
+                        return Body::from_stream(futures::stream::iter(chunks));
+                    }
+                    Body::from(data)
+                },
+            ),
+        );
+        let tracker = start_tracker(serve(app).await).await;
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("model");
+        std::fs::create_dir(&dest).unwrap();
+        std::fs::write(dest.join("a.txt"), "old a\n").unwrap();
+        std::fs::write(dest.join("b.txt"), "old b\n").unwrap();
+
+        // the failed run leaves the destination as it was
+        let options = options(tracker, &dest);
+        assert!(download(ROOT.to_string(), options.clone()).await.is_err());
+        assert_eq!(
+            std::fs::read_to_string(dest.join("a.txt")).unwrap(),
+            "old a\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dest.join("b.txt")).unwrap(),
+            "old b\n"
+        );
+        let staging = dir.path().join(".model.p2psync-staging");
+        assert_eq!(
+            std::fs::read_to_string(staging.join("a.txt")).unwrap(),
+            "new a\n"
+        );
+
+        // the next one only fetches what the staging directory is missing
+        download(ROOT.to_string(), options).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("a.txt")).unwrap(),
+            "new a\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dest.join("b.txt")).unwrap(),
+            "new b\n"
+        );
+        assert!(!staging.exists());
+        let mut fetched = fetched.lock().unwrap().clone();
+        fetched.sort();
+        assert_eq!(fetched, vec!["a.txt", "b.txt", "b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_download_seeds_to_running_server() {
+        let app =
+            peer_app().route(
+                "/download",
+                get(|Query(params): Query<HashMap<String, String>>| async move {
+                    file(&params["md5"]).1
+                }),
+            );
+        let tracker = start_tracker(serve(app).await).await;
+        let dir = tempfile::tempdir().unwrap();
+        let served = dir.path().join("served");
+        std::fs::create_dir(&served).unwrap();
+        std::fs::write(served.join("c.txt"), "c\n").unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let credentials = Credentials {
+            token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let serve_options = ServeOptions {
+            address: "127.0.0.1".to_string(),
+            port,
+            dump_path: None,
+            tracker: vec![],
+            watch: false,
+            hash_cache: None,
+            hash: None,
+            announce_all: true,
+            dht: None,
+            lan: false,
+            drain_timeout: Duration::from_secs(1),
+            credentials: credentials.clone(),
+            tls: None,
+            sign_key: None,
+            manifests: vec![],
+            tag: None,
+            seed_dir: Some(dir.path().to_path_buf()),
+        };
+        let server = tokio::spawn(crate::server::startup(
+            crate::server::CreateArgs::Pathes(vec![served.to_string_lossy().into_owned()]),
+            serve_options.clone(),
+        ));
+        let url = format!("http://127.0.0.1:{}", port);
+        let client = reqwest::Client::new();
+        for _ in 0..50 {
+            if client.get(format!("{}/health", url)).send().await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // the download hands the tree to the running server, which serves it
+        let dest = dir.path().join("model");
+        let mut options = options(tracker, &dest);
+        options.credentials = credentials;
+        options.seed = Some(serve_options);
+        download(ROOT.to_string(), options).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("b.txt")).unwrap(),
+            "new b\n"
+        );
+        let resp = client
+            .get(format!("{}/download?md5={}", url, id("new b\n")))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        assert_eq!(resp.text().await.unwrap(), "new b\n");
+        assert!(!server.is_finished());
+        server.abort();
+    }
 }
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+pub const PART_SUFFIX: &str = ".p2psync-part";
+pub const STATE_SUFFIX: &str = ".p2psync-state";
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct PartState {
+    md5: String,
+    size: u64,
+    // sorted, non-overlapping `start..end` ranges that are written and synced
+    done: Vec<(u64, u64)>,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// A download in progress. Data goes to `name.p2psync-part` next to the target
+/// and the byte ranges known to be on disk are recorded in
+/// `name.p2psync-state`, so an interrupted download of the same md5 resumes
+/// where it stopped. The part file is renamed over the target once complete.
+#[derive(Debug)]
+pub struct PartialDownload {
+    file_path: PathBuf,
+    part_path: PathBuf,
+    state_path: PathBuf,
+    state: PartState,
+}
+
+impl PartialDownload {
+    /// Opens the part file of `file_path` for a download of `md5`, keeping the
+    /// recorded ranges if an earlier attempt was for the same content.
+    pub fn open(file_path: &Path, md5: &str, size: u64) -> io::Result<(Self, fs::File)> {
+        let part_path = with_suffix(file_path, PART_SUFFIX);
+        let state_path = with_suffix(file_path, STATE_SUFFIX);
+
+        let previous = fs::read(state_path.as_path())
+            .ok()
+            .and_then(|data| serde_json::from_slice::<PartState>(&data).ok())
+            .filter(|state| state.md5 == md5 && state.size == size);
+        let part_len = fs::metadata(part_path.as_path()).map(|meta| meta.len());
+        let state = match (previous, part_len) {
+            (Some(state), Ok(len)) if state.done.iter().all(|&(_, end)| end <= len) => state,
+            _ => PartState {
+                md5: md5.to_string(),
+                size,
+                done: Vec::new(),
+            },
+        };
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(state.done.is_empty())
+            .open(part_path.as_path())?;
+
+        let partial = PartialDownload {
+            file_path: file_path.to_path_buf(),
+            part_path,
+            state_path,
+            state,
+        };
+        partial.save()?;
+        Ok((partial, file))
+    }
+
+    pub fn part_path(&self) -> &Path {
+        self.part_path.as_path()
+    }
+
+    /// Length of the completed range starting at offset zero.
+    pub fn prefix(&self) -> u64 {
+        match self.state.done.first() {
+            Some(&(0, end)) => end,
+            _ => 0,
+        }
+    }
+
+    /// Whether `start..end` is fully covered by recorded ranges.
+    pub fn contains(&self, start: u64, end: u64) -> bool {
+        self.state
+            .done
+            .iter()
+            .any(|&(done_start, done_end)| done_start <= start && end <= done_end)
+    }
+
+    /// Records `start..end` as written. The caller must have synced the data
+    /// to disk first, a recorded range is never fetched again.
+    pub fn mark_done(&mut self, start: u64, end: u64) -> io::Result<()> {
+        let done = &mut self.state.done;
+        done.push((start, end));
+        done.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(done.len());
+        for &(start, end) in done.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        *done = merged;
+        self.save()
+    }
+
+    /// Forgets every recorded range, e.g. when a peer sent a whole file where
+    /// a resumed range was asked for.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.state.done.clear();
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = serde_json::to_vec(&self.state).map_err(io::Error::other)?;
+        let tmp_path = with_suffix(self.state_path.as_path(), ".tmp");
+        fs::write(tmp_path.as_path(), data)?;
+        fs::rename(tmp_path, self.state_path.as_path())
+    }
+
+    /// Moves the verified part file over the target and drops the state.
+    pub fn finish(self) -> io::Result<()> {
+        fs::rename(self.part_path.as_path(), self.file_path.as_path())?;
+        remove_if_exists(self.state_path.as_path())
+    }
+
+    /// Removes the part file and its state, used when the content turned out
+    /// to be wrong so that the next attempt starts from scratch.
+    pub fn discard(self) -> io::Result<()> {
+        remove_if_exists(self.part_path.as_path())?;
+        remove_if_exists(self.state_path.as_path())
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartialDownload;
+    use std::io;
+
+    #[test]
+    fn test_resume_same_md5() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("file.bin");
+
+        let (mut partial, _) = PartialDownload::open(target.as_path(), "md5", 100)?;
+        partial.mark_done(0, 10)?;
+        partial.mark_done(20, 30)?;
+        partial.mark_done(10, 20)?;
+        partial.mark_done(50, 60)?;
+        assert_eq!(partial.state.done, vec![(0, 30), (50, 60)]);
+        assert!(partial.contains(5, 25));
+        assert!(!partial.contains(25, 55));
+        drop(partial);
+        std::fs::write(dir.path().join("file.bin.p2psync-part"), [0u8; 60])?;
+
+        let (partial, _) = PartialDownload::open(target.as_path(), "md5", 100)?;
+        assert_eq!(partial.prefix(), 30);
+        drop(partial);
+
+        let (partial, _) = PartialDownload::open(target.as_path(), "other", 100)?;
+        assert!(partial.state.done.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_finish_and_discard() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("file.bin");
+
+        let (partial, _) = PartialDownload::open(target.as_path(), "md5", 4)?;
+        std::fs::write(partial.part_path(), b"data")?;
+        partial.finish()?;
+        assert_eq!(std::fs::read(target.as_path())?, b"data");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        let (partial, _) = PartialDownload::open(dir.path().join("other").as_path(), "md5", 4)?;
+        partial.discard()?;
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+}
@@ -1,10 +1,21 @@
-use crate::server::LookupDirOrFile;
+use crate::auth::Credentials;
+use crate::dht::Dht;
+use crate::downloader::partial::{PART_SUFFIX, STATE_SUFFIX};
+use crate::hash::HashAlgo;
+use crate::lan::{self, LAN_GROUP};
+use crate::manifest::{PublicKey, SignedManifest};
+use crate::server::{FileStamp, HashCache, LookupDirOrFile, hash_file};
 use crate::tracker::PeersResponse;
 use futures::TryFutureExt;
-use reqwest;
+use rayon::prelude::*;
 use std::collections::VecDeque;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use std::{collections::HashSet, error::Error};
 
 #[derive(Debug, Clone)]
@@ -19,34 +30,134 @@ pub enum Action {
     MakeDir {
         path: PathBuf,
     },
+    Remove {
+        path: PathBuf,
+    },
+    RemoveDir {
+        path: PathBuf,
+    },
 }
 
 pub struct Planer {
     tracker_urls: Vec<String>,
+    dht_bootstrap: Vec<String>,
+    lan_window: Option<Duration>,
+    trusted_peer: Option<String>,
+    trusted_key: Option<PublicKey>,
+    strict: bool,
+    dest: Option<PathBuf>,
+    credentials: Credentials,
+    client: reqwest::Client,
 }
 
 impl Planer {
     pub fn new(tracker_urls: Vec<String>) -> Self {
-        Planer { tracker_urls }
+        Planer {
+            tracker_urls,
+            dht_bootstrap: Vec::new(),
+            lan_window: None,
+            trusted_peer: None,
+            trusted_key: None,
+            strict: false,
+            dest: None,
+            credentials: Credentials::default(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Also looks up the providers of the md5 in the DHT joined through the
+    /// `bootstrap` nodes, which works without any tracker.
+    pub fn with_dht(mut self, bootstrap: Vec<String>) -> Self {
+        self.dht_bootstrap = bootstrap;
+        self
+    }
+
+    /// Also listens for servers announcing themselves on the local network
+    /// for `window`.
+    pub fn with_lan(mut self, window: Option<Duration>) -> Self {
+        self.lan_window = window;
+        self
+    }
+
+    /// Authenticates to the trackers and peers with `credentials`. With a
+    /// token, only the peers a tracker lists and the trusted peer are used,
+    /// since anyone can publish to the DHT or the lan and would be sent the
+    /// token. DHT nodes are never sent it.
+    pub fn with_credentials(mut self, credentials: &Credentials) -> Self {
+        self.credentials = credentials.clone();
+        self.client = credentials.client_builder().build().unwrap();
+        self
+    }
+
+    /// Uses the tree served by `peer` as the reference instead of the one
+    /// most peers agree on. The peer is queried even if no tracker lists it.
+    pub fn with_trusted_peer(mut self, peer: Option<String>) -> Self {
+        self.trusted_peer = peer;
+        self
+    }
+
+    /// Only downloads the tree of a manifest signed by `key`, from the peers
+    /// serving exactly that tree. Takes precedence over a trusted peer.
+    pub fn with_trusted_key(mut self, key: Option<PublicKey>) -> Self {
+        self.trusted_key = key;
+        self
+    }
+
+    /// Fails the plan when any peer disagrees with the reference tree,
+    /// instead of excluding that peer.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Writes the root of the tree to `dest` instead of `./{name}`.
+    pub fn with_dest(mut self, dest: Option<PathBuf>) -> Self {
+        self.dest = dest;
+        self
     }
 
     pub async fn plan(&self, md5: &str) -> Result<Vec<Action>, Box<dyn Error + Sync + Send>> {
         let peers = {
             let mut peers_set = HashSet::new();
+            if !self.dht_bootstrap.is_empty() {
+                let dht =
+                    Dht::client(self.dht_bootstrap.clone()).with_credentials(&self.credentials);
+                dht.join().await;
+                peers_set.extend(dht.find_providers(md5).await);
+            }
+            let mut lan_others = Vec::new();
+            if let Some(window) = self.lan_window {
+                for (addr, md5s) in lan::discover(LAN_GROUP, Ipv4Addr::UNSPECIFIED, window).await? {
+                    if md5s.iter().any(|announced| announced == md5) {
+                        peers_set.insert(addr);
+                    } else {
+                        lan_others.push(addr);
+                    }
+                }
+            }
 
             let mut errs = Vec::new();
+            let mut listed = HashSet::new();
+            // only the peers that announced the id, trackers that do not
+            // filter by id answer with every peer
             for result in self
                 .tracker_urls
                 .iter()
-                .map(|url| reqwest::get(format!("{}/peers", url)))
+                .map(|url| self.client.get(format!("{}/peers?md5={}", url, md5)).send())
             {
                 match result
                     .and_then(async |r| r.error_for_status()?.json::<PeersResponse>().await)
                     .await
                 {
                     Ok(peers_response) => {
-                        for addr in peers_response.peers.iter().map(|p| p.addr.clone()) {
-                            peers_set.insert(addr);
+                        // peers failing the tracker's health probes are skipped
+                        for addr in peers_response
+                            .peers
+                            .iter()
+                            .filter(|p| !p.unhealthy)
+                            .map(|p| p.addr.clone())
+                        {
+                            listed.insert(addr);
                         }
                     }
                     Err(err) => {
@@ -54,9 +165,35 @@ impl Planer {
                     }
                 }
             }
+            peers_set.extend(listed.iter().cloned());
+            if peers_set.is_empty() {
+                peers_set.extend(lan_others);
+            }
+            if let Some(peer) = self.trusted_peer.as_ref() {
+                peers_set.insert(peer.clone());
+            }
+            if self.credentials.token.is_some() {
+                // peers announcing to a tracker proved they have the token
+                peers_set.retain(|peer| {
+                    let keep = listed.contains(peer) || self.trusted_peer.as_ref() == Some(peer);
+                    if !keep {
+                        eprintln!("skipping peer {}: no tracker lists it", peer);
+                    }
+                    keep
+                });
+            }
             if peers_set.is_empty() {
-                if errs.is_empty() {
+                if self.tracker_urls.is_empty()
+                    && self.dht_bootstrap.is_empty()
+                    && self.lan_window.is_none()
+                {
                     return Err("tracker_urls is empty".into());
+                } else if errs.is_empty() {
+                    return Err(format!(
+                        "no peers of {} found, servers only announce the ids of their roots unless started with --announce-all",
+                        md5
+                    )
+                    .into());
                 } else {
                     return Err(errs
                         .iter()
@@ -67,7 +204,7 @@ impl Planer {
                 }
             }
 
-            peers_set.iter().map(|str| str.clone()).collect::<Vec<_>>()
+            peers_set.iter().cloned().collect::<Vec<_>>()
         };
 
         // get md5 tree from peers
@@ -78,7 +215,9 @@ impl Planer {
             for (peer, result) in peers.iter().map(|peer| {
                 (
                     peer.as_str(),
-                    reqwest::get(format!("{}/query?md5={}", peer.as_str(), md5)),
+                    self.client
+                        .get(format!("{}/query?md5={}", peer.as_str(), md5))
+                        .send(),
                 )
             }) {
                 match result
@@ -94,8 +233,8 @@ impl Planer {
                 }
             }
 
-            if tree_and_peer.len() == 0 {
-                return if errs.len() == 0 {
+            if tree_and_peer.is_empty() {
+                return if errs.is_empty() {
                     Err("no peers found".into())
                 } else {
                     Err(errs
@@ -107,18 +246,22 @@ impl Planer {
                 };
             }
 
-            let (peer, tree) = tree_and_peer.pop().unwrap();
-
-            let mut new_peers = Vec::new();
-
-            new_peers.push(String::from(peer));
-
-            while let Some((peer, _)) = tree_and_peer.pop() {
-                new_peers.push(String::from(peer))
+            let (tree, new_peers, excluded) = match self.trusted_key.as_ref() {
+                Some(key) => {
+                    let signed = self.signed_tree(&tree_and_peer, md5, key).await?;
+                    signed_consensus(tree_and_peer, signed, self.strict)?
+                }
+                None => consensus(tree_and_peer, self.trusted_peer.as_deref(), self.strict)?,
+            };
+            for peer in excluded.iter() {
+                eprintln!(
+                    "excluding peer {}: its tree differs from the agreed one",
+                    peer
+                );
             }
-
             (tree, new_peers)
         };
+        validate_tree(&tree)?;
 
         let n_peers = new_peers.len();
         let mut next_id: usize = 0;
@@ -126,35 +269,35 @@ impl Planer {
         let peers_ptr = Arc::new(RwLock::new(new_peers));
 
         {
+            let root = match self.dest.as_ref() {
+                Some(dest) => dest.clone(),
+                None => Path::new(".").join(entry_name(&tree)),
+            };
             let mut frontier = VecDeque::new();
-            frontier.push_back((PathBuf::from("."), &tree));
+            frontier.push_back((root, &tree));
 
             let mut result = Vec::new();
 
             while !frontier.is_empty() {
-                let (prefix, tree) = frontier.pop_front().unwrap();
+                let (cur_path, tree) = frontier.pop_front().unwrap();
 
                 match tree {
-                    LookupDirOrFile::Dir { name, children } => {
-                        let cur_path = prefix.join(name);
-
+                    LookupDirOrFile::Dir { children, .. } => {
                         result.push(Action::MakeDir {
                             path: cur_path.clone(),
                         });
 
                         for child in children.iter() {
-                            frontier.push_back((cur_path.clone(), &child));
+                            frontier.push_back((cur_path.join(entry_name(child)), child));
                         }
                     }
-                    LookupDirOrFile::File { name, md5, size } => {
-                        let cur_path = prefix.join(name);
-
+                    LookupDirOrFile::File { md5, size, .. } => {
                         result.push(Action::Download {
                             peers: peers_ptr.clone(),
                             peer_id: next_id,
                             path: cur_path,
                             md5: md5.clone(),
-                            size: size.clone(),
+                            size: *size,
                         });
 
                         next_id += 1;
@@ -165,6 +308,424 @@ impl Planer {
             Ok(result)
         }
     }
+
+    /// The tree of the first manifest of `md5` served by one of the peers
+    /// that is signed by `key`.
+    async fn signed_tree(
+        &self,
+        tree_and_peer: &[(&str, LookupDirOrFile)],
+        md5: &str,
+        key: &PublicKey,
+    ) -> Result<LookupDirOrFile, String> {
+        let mut errs = Vec::new();
+        for (peer, _) in tree_and_peer.iter() {
+            let result = self
+                .client
+                .get(format!("{}/manifest?md5={}", peer, md5))
+                .send()
+                .and_then(async |r| r.error_for_status()?.json::<SignedManifest>().await)
+                .await
+                .map_err(|err| err.to_string())
+                .and_then(|signed| signed.verify(key));
+            match result {
+                Ok(manifest) if manifest.md5 == md5 => return Ok(manifest.tree),
+                Ok(manifest) => errs.push(format!(
+                    "{}: manifest is for {}, not {}",
+                    peer, manifest.md5, md5
+                )),
+                Err(err) => errs.push(format!("{}: {}", peer, err)),
+            }
+        }
+        Err(format!(
+            "no manifest of {} signed by {}: {}",
+            md5,
+            key,
+            errs.join("; ")
+        ))
+    }
+}
+
+/// Whether two peers serve the same tree. The name of the root is where a
+/// peer keeps the tree, a seeded download may keep it under another name, so
+/// like the id it is not compared.
+fn same_tree(a: &LookupDirOrFile, b: &LookupDirOrFile) -> bool {
+    match (a, b) {
+        (LookupDirOrFile::Dir { children: a, .. }, LookupDirOrFile::Dir { children: b, .. }) => {
+            a == b
+        }
+        (
+            LookupDirOrFile::File {
+                md5: a,
+                size: a_size,
+                ..
+            },
+            LookupDirOrFile::File {
+                md5: b,
+                size: b_size,
+                ..
+            },
+        ) => a == b && a_size == b_size,
+        _ => false,
+    }
+}
+
+fn root_name(tree: &LookupDirOrFile) -> &str {
+    match tree {
+        LookupDirOrFile::Dir { name, .. } | LookupDirOrFile::File { name, .. } => name,
+    }
+}
+
+fn set_root_name(tree: &mut LookupDirOrFile, root: String) {
+    match tree {
+        LookupDirOrFile::Dir { name, .. } | LookupDirOrFile::File { name, .. } => *name = root,
+    }
+}
+
+/// Like [`consensus`], with the tree of a signed manifest as the reference.
+/// Peers serving any other tree are excluded, however many they are.
+fn signed_consensus(
+    tree_and_peer: Vec<(&str, LookupDirOrFile)>,
+    signed: LookupDirOrFile,
+    strict: bool,
+) -> Result<(LookupDirOrFile, Vec<String>, Vec<String>), String> {
+    let (peers, excluded): (Vec<_>, Vec<_>) = tree_and_peer
+        .into_iter()
+        .partition(|(_, tree)| same_tree(tree, &signed));
+    let peers = peers
+        .into_iter()
+        .map(|(peer, _)| peer.to_string())
+        .collect::<Vec<_>>();
+    let excluded = excluded
+        .into_iter()
+        .map(|(peer, _)| peer.to_string())
+        .collect::<Vec<_>>();
+    if peers.is_empty() {
+        return Err(format!(
+            "tree mismatch: no peer serves the signed tree, {} do not",
+            excluded.join(", ")
+        ));
+    }
+    if strict && !excluded.is_empty() {
+        return Err(format!(
+            "tree mismatch: peers {} disagree with the signed tree",
+            excluded.join(", ")
+        ));
+    }
+    Ok((signed, peers, excluded))
+}
+
+/// Picks the tree to download among the ones returned by the peers. The
+/// reference is the tree of `trusted_peer` if given, otherwise the tree a
+/// strict majority of the peers returned. Returns the reference, the peers
+/// serving it and the peers that were excluded because they disagree, or a
+/// "tree mismatch" error if there is no reference or `strict` is set and a
+/// peer disagrees.
+fn consensus(
+    tree_and_peer: Vec<(&str, LookupDirOrFile)>,
+    trusted_peer: Option<&str>,
+    strict: bool,
+) -> Result<(LookupDirOrFile, Vec<String>, Vec<String>), String> {
+    let n_peers = tree_and_peer.len();
+    // peers grouped by the tree they returned, with the names of its root
+    let mut groups: Vec<(LookupDirOrFile, Vec<String>, Vec<String>)> = Vec::new();
+    for (peer, tree) in tree_and_peer.into_iter() {
+        let name = root_name(&tree).to_string();
+        match groups
+            .iter_mut()
+            .find(|(other, _, _)| same_tree(other, &tree))
+        {
+            Some((_, peers, names)) => {
+                peers.push(String::from(peer));
+                names.push(name);
+            }
+            None => groups.push((tree, vec![String::from(peer)], vec![name])),
+        }
+    }
+
+    let reference = match trusted_peer {
+        Some(trusted) => groups
+            .iter()
+            .position(|(_, peers, _)| peers.iter().any(|peer| peer == trusted))
+            .ok_or_else(|| format!("tree mismatch: trusted peer {} did not answer", trusted))?,
+        None => {
+            let (reference, (_, peers, _)) = groups
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (_, peers, _))| peers.len())
+                .unwrap();
+            if peers.len() * 2 <= n_peers {
+                return Err(format!(
+                    "tree mismatch: {} peers returned {} different trees and no tree has a majority",
+                    n_peers,
+                    groups.len()
+                ));
+            }
+            reference
+        }
+    };
+
+    let (mut tree, peers, names) = groups.swap_remove(reference);
+    // the name most peers keep the root under, the first one on a tie
+    let name = names
+        .iter()
+        .rev()
+        .max_by_key(|name| names.iter().filter(|other| other == name).count())
+        .unwrap();
+    set_root_name(&mut tree, name.clone());
+    let excluded = groups
+        .into_iter()
+        .flat_map(|(_, peers, _)| peers)
+        .collect::<Vec<_>>();
+    if strict && !excluded.is_empty() {
+        return Err(format!(
+            "tree mismatch: peers {} disagree with {}",
+            excluded.join(", "),
+            peers.join(", ")
+        ));
+    }
+    Ok((tree, peers, excluded))
+}
+
+/// Why the tree returned by the peers was rejected: following it would write
+/// outside the destination, or write twice to the same path.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnsafeTree {
+    /// The name is empty, `.` or `..`, or contains a path separator or NUL.
+    InvalidName { dir: PathBuf, name: String },
+    /// The name appears more than once in the same directory.
+    DuplicateName { dir: PathBuf, name: String },
+}
+
+impl fmt::Display for UnsafeTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnsafeTree::InvalidName { dir, name } => {
+                write!(
+                    f,
+                    "unsafe tree: invalid name {:?} in {}",
+                    name,
+                    dir.display()
+                )
+            }
+            UnsafeTree::DuplicateName { dir, name } => {
+                write!(
+                    f,
+                    "unsafe tree: duplicate name {:?} in {}",
+                    name,
+                    dir.display()
+                )
+            }
+        }
+    }
+}
+
+impl Error for UnsafeTree {}
+
+/// Checks that every name of `tree` is a single normal path component and
+/// that no directory has two entries with the same name, so the plan stays
+/// below the destination whatever the peers returned.
+fn validate_tree(tree: &LookupDirOrFile) -> Result<(), UnsafeTree> {
+    fn check_name(dir: &Path, name: &str) -> Result<(), UnsafeTree> {
+        let invalid =
+            name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']);
+        if invalid {
+            Err(UnsafeTree::InvalidName {
+                dir: dir.to_path_buf(),
+                name: name.to_string(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    let mut frontier = vec![(PathBuf::from("."), tree)];
+    check_name(Path::new("."), entry_name(tree))?;
+    while let Some((prefix, tree)) = frontier.pop() {
+        if let LookupDirOrFile::Dir { name, children } = tree {
+            let dir = prefix.join(name);
+            let mut names = HashSet::new();
+            for child in children.iter() {
+                let child_name = entry_name(child);
+                check_name(&dir, child_name)?;
+                if !names.insert(child_name) {
+                    return Err(UnsafeTree::DuplicateName {
+                        dir,
+                        name: child_name.to_string(),
+                    });
+                }
+                frontier.push((dir.clone(), child));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn entry_name(tree: &LookupDirOrFile) -> &str {
+    match tree {
+        LookupDirOrFile::Dir { name, .. } | LookupDirOrFile::File { name, .. } => name,
+    }
+}
+
+/// Files of a plan that were already present with the right content.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reused {
+    pub files: usize,
+    pub bytes: usize,
+}
+
+/// Checks whether `path` already holds a file of `size` bytes whose md5 is
+/// `md5`. Returns the stamp and md5 of the local file when it had to be
+/// hashed, so the caller can add it to its cache.
+fn check_present(
+    path: &Path,
+    md5: &str,
+    size: usize,
+    cache: &HashCache,
+) -> io::Result<(bool, Option<(FileStamp, String)>)> {
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((false, None)),
+        Err(err) => return Err(err),
+    };
+    if !meta.is_file() || meta.len() != size as u64 {
+        return Ok((false, None));
+    }
+
+    let algo = HashAlgo::of_id(md5)?;
+    let stamp = FileStamp::of(&meta);
+    match cache.get_as(&stamp, algo) {
+        Some(local_md5) => Ok((local_md5 == md5, None)),
+        None => {
+            let local_md5 = hash_file(path, algo)?;
+            Ok((local_md5 == md5, Some((stamp, local_md5))))
+        }
+    }
+}
+
+/// Drops the downloads whose destination already holds a file with the same
+/// size and md5. Local files are hashed unless their stamp is in `cache`, and
+/// every file hashed here is added to it.
+pub fn skip_present(
+    actions: Vec<Action>,
+    cache: &mut HashCache,
+) -> io::Result<(Vec<Action>, Reused)> {
+    let checks = actions
+        .par_iter()
+        .map(|action| match action {
+            Action::Download {
+                path, md5, size, ..
+            } => check_present(path, md5, *size, cache),
+            _ => Ok((false, None)),
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut reused = Reused::default();
+    let mut needed = Vec::new();
+    for (action, (present, hashed)) in actions.into_iter().zip(checks) {
+        if let Some((stamp, md5)) = hashed {
+            cache.insert(stamp, md5);
+        }
+        match action {
+            Action::Download { size, .. } if present => {
+                reused.files += 1;
+                reused.bytes += size;
+            }
+            action => needed.push(action),
+        }
+    }
+    Ok((needed, reused))
+}
+
+/// Fails unless `path` lies strictly below `root` and neither contains a
+/// `..` component, so a removal can never reach outside the target tree.
+fn check_inside(root: &Path, path: &Path) -> io::Result<()> {
+    let inside = !root.components().any(|c| c == Component::ParentDir)
+        && path
+            .strip_prefix(root)
+            .is_ok_and(|rest| rest.components().all(|c| matches!(c, Component::Normal(_))))
+        && path != root;
+    if inside {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "refusing to delete {} outside of {}",
+                path.display(),
+                root.display()
+            ),
+        ))
+    }
+}
+
+/// Plans the removals that make the local copy of a directory tree match the
+/// remote one exactly. Everything below the root of `actions` that the plan
+/// neither creates nor downloads is removed, except the part files of planned
+/// downloads so interrupted downloads still resume. A local file where the
+/// plan has a directory, or a directory where it has a file, is removed too.
+pub fn plan_removals(actions: &[Action]) -> io::Result<Vec<Action>> {
+    let root = match actions.first() {
+        Some(Action::MakeDir { path }) => path.clone(),
+        // a single file has nothing beside it to remove
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut dirs = HashSet::new();
+    let mut files = HashSet::new();
+    for action in actions.iter() {
+        match action {
+            Action::MakeDir { path } => {
+                dirs.insert(path.clone());
+            }
+            Action::Download { path, .. } => {
+                for suffix in ["", PART_SUFFIX, STATE_SUFFIX] {
+                    let mut name = OsString::from(path.as_os_str());
+                    name.push(suffix);
+                    files.insert(PathBuf::from(name));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut removals = Vec::new();
+    let mut frontier = VecDeque::new();
+    match std::fs::symlink_metadata(root.as_path()) {
+        Ok(meta) if meta.is_dir() => frontier.push_back(root.clone()),
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a directory", root.display()),
+            ));
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    while let Some(dir) = frontier.pop_front() {
+        let mut entries = std::fs::read_dir(dir.as_path())?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for path in entries {
+            // symlinks are removed as links, never followed
+            let is_dir = std::fs::symlink_metadata(path.as_path())?.is_dir();
+            if is_dir && dirs.contains(&path) {
+                frontier.push_back(path);
+            } else if !is_dir && files.contains(&path) {
+                // kept, the download replaces it if its content differs
+            } else {
+                check_inside(root.as_path(), path.as_path())?;
+                removals.push(if is_dir {
+                    Action::RemoveDir { path }
+                } else {
+                    Action::Remove { path }
+                });
+            }
+        }
+    }
+    Ok(removals)
 }
 
 #[cfg(test)]
@@ -172,24 +733,23 @@ mod tests {
     use super::*;
     use crate::server::LookupDirOrFile;
     use crate::tracker::{PeerInfo, PeersResponse};
-    use axum::{Router, extract::Query, response::Json, routing::get};
+    use axum::{Router, extract::Query, middleware::Next, response::Json, routing::get};
     use std::collections::HashMap;
     use std::net::SocketAddr;
     use tokio::net::TcpListener;
 
     // Mock tracker server that returns predefined peers
-    async fn mock_tracker_handler() -> Json<PeersResponse> {
-        let peers = vec![
-            PeerInfo {
-                addr: "http://127.0.0.1:18081".to_string(),
-                last_seen: 1234567890,
-            },
-            PeerInfo {
-                addr: "http://127.0.0.1:18082".to_string(),
+    fn mock_tracker_response(peer_ports: &[u16]) -> PeersResponse {
+        let peers = peer_ports
+            .iter()
+            .map(|port| PeerInfo {
+                addr: format!("http://127.0.0.1:{}", port),
                 last_seen: 1234567890,
-            },
-        ];
-        Json(PeersResponse { peers })
+                unconfirmed: false,
+                unhealthy: false,
+            })
+            .collect();
+        PeersResponse { peers }
     }
 
     // Mock tracker that returns no peers
@@ -261,9 +821,15 @@ mod tests {
         })
     }
 
-    async fn start_mock_tracker_server(port: u16) -> tokio::task::JoinHandle<()> {
+    async fn start_mock_tracker_server(
+        port: u16,
+        peer_ports: [u16; 2],
+    ) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
-            let app = Router::new().route("/peers", get(mock_tracker_handler));
+            let app = Router::new().route(
+                "/peers",
+                get(move || async move { Json(mock_tracker_response(&peer_ports)) }),
+            );
             let addr = SocketAddr::from(([127, 0, 0, 1], port));
             let listener = TcpListener::bind(addr).await.unwrap();
             axum::serve(listener, app).await.unwrap();
@@ -286,7 +852,7 @@ mod tests {
     #[tokio::test]
     async fn test_plan_single_file() {
         // Start mock servers
-        let _tracker_handle = start_mock_tracker_server(18080).await;
+        let _tracker_handle = start_mock_tracker_server(18080, [18081, 18082]).await;
         let _peer1_handle = start_mock_peer_server(18081, false).await;
         let _peer2_handle = start_mock_peer_server(18082, false).await;
 
@@ -309,7 +875,7 @@ mod tests {
     #[tokio::test]
     async fn test_plan_directory_with_files() {
         // Start mock servers
-        let _tracker_handle = start_mock_tracker_server(18083).await;
+        let _tracker_handle = start_mock_tracker_server(18083, [18084, 18085]).await;
         let _peer1_handle = start_mock_peer_server(18084, false).await;
         let _peer2_handle = start_mock_peer_server(18085, false).await;
 
@@ -339,12 +905,34 @@ mod tests {
                 _ => panic!("Expected Download action"),
             }
         }
+
+        // with a destination, the root is written there under any name
+        let planer = Planer::new(vec!["http://127.0.0.1:18083".to_string()])
+            .with_dest(Some(PathBuf::from("/srv/current")));
+        let paths = planer
+            .plan("test_dir_md5")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|action| match action {
+                Action::MakeDir { path } | Action::Download { path, .. } => path,
+                _ => panic!("unexpected {:?}", action),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("/srv/current"),
+                PathBuf::from("/srv/current/file1.txt"),
+                PathBuf::from("/srv/current/file2.txt"),
+            ]
+        );
     }
 
     #[tokio::test]
     async fn test_plan_nested_directory() {
         // Start mock servers
-        let _tracker_handle = start_mock_tracker_server(18086).await;
+        let _tracker_handle = start_mock_tracker_server(18086, [18087, 18088]).await;
         let _peer1_handle = start_mock_peer_server(18087, false).await;
         let _peer2_handle = start_mock_peer_server(18088, false).await;
 
@@ -363,6 +951,7 @@ mod tests {
             match action {
                 Action::MakeDir { .. } => make_dir_count += 1,
                 Action::Download { .. } => download_count += 1,
+                _ => panic!("unexpected {:?}", action),
             }
         }
 
@@ -400,10 +989,14 @@ mod tests {
                 PeerInfo {
                     addr: "http://127.0.0.1:19081".to_string(),
                     last_seen: 1234567890,
+                    unconfirmed: false,
+                    unhealthy: false,
                 },
                 PeerInfo {
                     addr: "http://127.0.0.1:19082".to_string(),
                     last_seen: 1234567890,
+                    unconfirmed: false,
+                    unhealthy: false,
                 },
             ];
             Json(PeersResponse { peers })
@@ -432,8 +1025,8 @@ mod tests {
     #[tokio::test]
     async fn test_plan_multiple_trackers() {
         // Start multiple tracker servers (though they return same data)
-        let _tracker1_handle = start_mock_tracker_server(18092).await;
-        let _tracker2_handle = start_mock_tracker_server(18093).await;
+        let _tracker1_handle = start_mock_tracker_server(18092, [18094, 18095]).await;
+        let _tracker2_handle = start_mock_tracker_server(18093, [18094, 18095]).await;
         let _peer1_handle = start_mock_peer_server(18094, false).await;
         let _peer2_handle = start_mock_peer_server(18095, false).await;
 
@@ -459,7 +1052,7 @@ mod tests {
     #[tokio::test]
     async fn test_action_peer_round_robin() {
         // Start mock servers
-        let _tracker_handle = start_mock_tracker_server(18096).await;
+        let _tracker_handle = start_mock_tracker_server(18096, [18097, 18098]).await;
         let _peer1_handle = start_mock_peer_server(18097, false).await;
         let _peer2_handle = start_mock_peer_server(18098, false).await;
 
@@ -483,6 +1076,289 @@ mod tests {
         assert_eq!(download_actions[1], 1);
     }
 
+    #[test]
+    fn test_skip_present() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("same.txt"), b"same")?;
+        std::fs::write(dir.path().join("changed.txt"), b"chxnged")?;
+        std::fs::write(dir.path().join("resized.txt"), b"resized!")?;
+        let peers = Arc::new(RwLock::new(vec!["http://127.0.0.1:1".to_string()]));
+        let download = |name: &str, content: &[u8]| Action::Download {
+            peers: peers.clone(),
+            peer_id: 0,
+            path: dir.path().join(name),
+            md5: format!("{:x}", md5::compute(content)),
+            size: content.len(),
+        };
+        let actions = vec![
+            Action::MakeDir {
+                path: dir.path().to_path_buf(),
+            },
+            download("same.txt", b"same"),
+            download("changed.txt", b"changed"),
+            download("resized.txt", b"resized"),
+            download("missing.txt", b"missing"),
+        ];
+
+        let mut cache = HashCache::new();
+        let (needed, reused) = skip_present(actions.clone(), &mut cache)?;
+        assert_eq!(reused, Reused { files: 1, bytes: 4 });
+        assert_eq!(needed.len(), 4);
+        assert!(needed.iter().all(|action| match action {
+            Action::Download { path, .. } => !path.ends_with("same.txt"),
+            _ => true,
+        }));
+        // the two files of the right size were hashed and cached
+        assert_eq!(cache.len(), 2);
+
+        let (needed, reused) = skip_present(actions, &mut cache)?;
+        assert_eq!(reused.files, 1);
+        assert_eq!(needed.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_removals() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("keep/stale_dir/inner"))?;
+        std::fs::create_dir_all(root.join("file_now_dir"))?;
+        std::fs::write(root.join("keep/a.txt"), b"a")?;
+        std::fs::write(root.join("keep/a.txt.p2psync-part"), b"a")?;
+        std::fs::write(root.join("keep/stale.txt"), b"stale")?;
+        std::fs::write(root.join("dir_now_file"), b"file")?;
+        std::fs::write(dir.path().join("outside.txt"), b"outside")?;
+
+        let peers = Arc::new(RwLock::new(Vec::new()));
+        let download = |path: PathBuf| Action::Download {
+            peers: peers.clone(),
+            peer_id: 0,
+            path,
+            md5: "md5".to_string(),
+            size: 1,
+        };
+        let actions = vec![
+            Action::MakeDir { path: root.clone() },
+            Action::MakeDir {
+                path: root.join("keep"),
+            },
+            Action::MakeDir {
+                path: root.join("dir_now_file"),
+            },
+            download(root.join("keep/a.txt")),
+            download(root.join("file_now_dir")),
+        ];
+
+        let mut removed = plan_removals(&actions)?
+            .into_iter()
+            .map(|action| match action {
+                Action::Remove { path } => (path, false),
+                Action::RemoveDir { path } => (path, true),
+                action => panic!("unexpected {:?}", action),
+            })
+            .collect::<Vec<_>>();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                (root.join("dir_now_file"), false),
+                (root.join("file_now_dir"), true),
+                (root.join("keep/stale.txt"), false),
+                (root.join("keep/stale_dir"), true),
+            ]
+        );
+
+        // a single file plan never removes anything
+        assert!(plan_removals(&actions[3..])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_inside() {
+        let root = Path::new("./root");
+        assert!(check_inside(root, Path::new("./root/a/b")).is_ok());
+        assert!(check_inside(root, Path::new("./root")).is_err());
+        assert!(check_inside(root, Path::new("./other/a")).is_err());
+        assert!(check_inside(root, Path::new("./root/../a")).is_err());
+        assert!(check_inside(Path::new("./.."), Path::new("./../a")).is_err());
+    }
+
+    #[test]
+    fn test_consensus() {
+        let file = |md5: &str| LookupDirOrFile::File {
+            name: "a.txt".to_string(),
+            md5: md5.to_string(),
+            size: 1,
+        };
+        let trees = || {
+            vec![
+                ("p1", file("good")),
+                ("p2", file("bad")),
+                ("p3", file("good")),
+            ]
+        };
+
+        // the majority wins and the odd peer is excluded
+        let (tree, peers, excluded) = consensus(trees(), None, false).unwrap();
+        assert_eq!(tree, file("good"));
+        assert_eq!(peers, vec!["p1", "p3"]);
+        assert_eq!(excluded, vec!["p2"]);
+
+        // a trusted peer overrules the majority
+        let (tree, peers, excluded) = consensus(trees(), Some("p2"), false).unwrap();
+        assert_eq!(tree, file("bad"));
+        assert_eq!(peers, vec!["p2"]);
+        assert_eq!(excluded, vec!["p1", "p3"]);
+        let err = consensus(trees(), Some("p4"), false).unwrap_err();
+        assert!(err.contains("tree mismatch"));
+
+        // strict mode fails on any disagreement
+        let err = consensus(trees(), None, true).unwrap_err();
+        assert!(err.contains("tree mismatch"));
+        assert!(consensus(vec![("p1", file("good"))], None, true).is_ok());
+
+        // a tie has no majority
+        let err =
+            consensus(vec![("p1", file("good")), ("p2", file("bad"))], None, false).unwrap_err();
+        assert!(err.contains("tree mismatch"));
+
+        // a root kept under another name is the same tree
+        let renamed = LookupDirOrFile::File {
+            name: "copy.txt".to_string(),
+            md5: "bad".to_string(),
+            size: 1,
+        };
+        let (tree, peers, _) = consensus(
+            vec![("p1", renamed), ("p2", file("bad")), ("p3", file("bad"))],
+            None,
+            true,
+        )
+        .unwrap();
+        // and it is named as most peers name it
+        assert_eq!(tree, file("bad"));
+        assert_eq!(peers, vec!["p1", "p2", "p3"]);
+    }
+
+    #[test]
+    fn test_signed_consensus() {
+        let file = |md5: &str| LookupDirOrFile::File {
+            name: "a.txt".to_string(),
+            md5: md5.to_string(),
+            size: 1,
+        };
+        let trees = || {
+            vec![
+                ("p1", file("forged")),
+                ("p2", file("signed")),
+                ("p3", file("forged")),
+            ]
+        };
+
+        // the signed tree wins over the majority
+        let (tree, peers, excluded) = signed_consensus(trees(), file("signed"), false).unwrap();
+        assert_eq!(tree, file("signed"));
+        assert_eq!(peers, vec!["p2"]);
+        assert_eq!(excluded, vec!["p1", "p3"]);
+
+        let err = signed_consensus(trees(), file("other"), false).unwrap_err();
+        assert!(err.contains("tree mismatch"));
+        let err = signed_consensus(trees(), file("signed"), true).unwrap_err();
+        assert!(err.contains("tree mismatch"));
+    }
+
+    #[test]
+    fn test_validate_tree() {
+        let file = |name: &str| LookupDirOrFile::File {
+            name: name.to_string(),
+            md5: "md5".to_string(),
+            size: 1,
+        };
+        let dir = |name: &str, children: Vec<LookupDirOrFile>| LookupDirOrFile::Dir {
+            name: name.to_string(),
+            children,
+        };
+        let invalid = |dir: &str, name: &str| UnsafeTree::InvalidName {
+            dir: PathBuf::from(dir),
+            name: name.to_string(),
+        };
+
+        assert_eq!(
+            validate_tree(&dir("root", vec![file("a"), dir("..b", vec![file(".c")])])),
+            Ok(())
+        );
+        for name in [
+            "../../etc/cron.d/x",
+            "/etc/passwd",
+            "a/b",
+            "a\\b",
+            "..",
+            ".",
+            "",
+            "a\0b",
+        ] {
+            assert_eq!(validate_tree(&file(name)), Err(invalid(".", name)));
+            assert_eq!(
+                validate_tree(&dir("root", vec![dir("sub", vec![file(name)])])),
+                Err(invalid("./root/sub", name))
+            );
+        }
+        assert_eq!(
+            validate_tree(&dir(
+                "root",
+                vec![file("a"), dir("b", vec![]), dir("a", vec![])]
+            )),
+            Err(UnsafeTree::DuplicateName {
+                dir: PathBuf::from("./root"),
+                name: "a".to_string(),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plan_rejects_unsafe_tree() {
+        // a peer whose tree would write outside the destination
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = peer.local_addr().unwrap().port();
+        let peer_app = Router::new().route(
+            "/query",
+            get(|| async {
+                Json(LookupDirOrFile::Dir {
+                    name: "root".to_string(),
+                    children: vec![LookupDirOrFile::File {
+                        name: "../../etc/cron.d/x".to_string(),
+                        md5: "md5".to_string(),
+                        size: 1,
+                    }],
+                })
+            }),
+        );
+        tokio::spawn(async move { axum::serve(peer, peer_app).await.unwrap() });
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_url = format!("http://{}", tracker.local_addr().unwrap());
+        let tracker_app = Router::new().route(
+            "/peers",
+            get(move || async move { Json(mock_tracker_response(&[port])) }),
+        );
+        tokio::spawn(async move { axum::serve(tracker, tracker_app).await.unwrap() });
+
+        // the plan fails with the typed error
+        let err = Planer::new(vec![tracker_url])
+            .plan("root_md5")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsafeTree>(),
+            Some(&UnsafeTree::InvalidName {
+                dir: PathBuf::from("./root"),
+                name: "../../etc/cron.d/x".to_string(),
+            })
+        );
+        assert_eq!(
+            err.to_string(),
+            "unsafe tree: invalid name \"../../etc/cron.d/x\" in ./root"
+        );
+    }
+
     #[tokio::test]
     async fn test_plan_no_peers_available() {
         // Start a tracker server that returns empty peers list
@@ -501,8 +1377,88 @@ mod tests {
 
         assert!(result.is_err());
         let error_msg = result.unwrap_err().to_string();
+        assert!(error_msg.contains("no peers of test_md5 found"));
+    }
+
+    #[tokio::test]
+    async fn test_plan_sends_token_to_listed_peers_only() {
+        // a server that publishes the id to the DHT and records whether each
+        // request carried a token
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let addr = format!("http://127.0.0.1:{}", port);
+        let dht = Arc::new(Dht::node(addr.clone(), Vec::new()));
+        dht.publish(&["test_file_md5".to_string()]).await;
+        let tokens = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = tokens.clone();
+        let app =
+            dht.router()
+                .route("/query", get(mock_peer_handler))
+                .layer(axum::middleware::from_fn(
+                    move |req: axum::extract::Request, next: Next| {
+                        recorded.lock().unwrap().push((
+                            req.uri().path().to_string(),
+                            req.headers().contains_key("authorization"),
+                        ));
+                        next.run(req)
+                    },
+                ));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let credentials = Credentials::new(Some("s3cret".to_string()));
+
+        // found through the DHT only, it is neither asked nor sent the token
+        let planer = Planer::new(Vec::new())
+            .with_dht(vec![addr.clone()])
+            .with_credentials(&credentials);
+        assert!(planer.plan("test_file_md5").await.is_err());
+        let sent = std::mem::take(&mut *tokens.lock().unwrap());
+        assert!(!sent.is_empty());
         assert!(
-            error_msg.contains("tracker_urls is empty") || error_msg.contains("no peers found")
+            sent.iter()
+                .all(|(path, token)| path.starts_with("/dht/") && !token)
+        );
+
+        // listed by a tracker, it is
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_url = format!("http://{}", tracker.local_addr().unwrap());
+        let tracker_app = Router::new().route(
+            "/peers",
+            get(move || async move { Json(mock_tracker_response(&[port])) }),
         );
+        tokio::spawn(async move { axum::serve(tracker, tracker_app).await.unwrap() });
+        let planer = Planer::new(vec![tracker_url])
+            .with_dht(vec![addr])
+            .with_credentials(&credentials);
+        assert!(planer.plan("test_file_md5").await.is_ok());
+        assert!(
+            tokens
+                .lock()
+                .unwrap()
+                .contains(&("/query".to_string(), true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plan_only_asks_peers_of_the_id() {
+        // the tracker knows a peer, but not as a peer of the id
+        async fn peers_handler(
+            Query(params): Query<HashMap<String, String>>,
+        ) -> Json<PeersResponse> {
+            match params.get("md5") {
+                Some(_) => Json(PeersResponse { peers: vec![] }),
+                None => Json(mock_tracker_response(&[18102])),
+            }
+        }
+        let _tracker_handle = tokio::spawn(async move {
+            let app = Router::new().route("/peers", get(peers_handler));
+            let listener = TcpListener::bind("127.0.0.1:18101").await.unwrap();
+            axum::serve(listener, app).await.unwrap();
+        });
+        let _peer_handle = start_mock_peer_server(18102, false).await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+
+        let planer = Planer::new(vec!["http://127.0.0.1:18101".to_string()]);
+        let err = planer.plan("test_file_md5").await.unwrap_err();
+        assert!(err.to_string().contains("no peers of test_file_md5 found"));
     }
 }
@@ -0,0 +1,84 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::hash::HashAlgo;
+use crate::server::{
+    CreateArgs, FileStamp, HashCache, SeedRequest, SeedResponse, ServeOptions, startup,
+};
+use crate::tls;
+use reqwest::StatusCode;
+
+/// Serves the tree at `root`, downloaded as `md5`, from the server running at
+/// the address of `options`, or from a new one started with `options` if none
+/// answers there. `files` holds the stamp and id of every verified file, so
+/// neither server hashes them again. Starting a server only returns once it
+/// is shut down.
+pub async fn seed(
+    root: &Path,
+    md5: &str,
+    files: Vec<(FileStamp, String)>,
+    mut options: ServeOptions,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let path = std::fs::canonicalize(root)?;
+    // a server bound to every address is asked on the loopback
+    let host = match options.address.parse::<IpAddr>() {
+        Ok(ip) if ip.is_unspecified() => "127.0.0.1".to_string(),
+        _ => options.address.clone(),
+    };
+    let url = format!(
+        "{}://{}:{}",
+        tls::scheme(options.tls.as_ref()),
+        host,
+        options.port
+    );
+    let client = options.credentials.client_builder().build()?;
+    let request = SeedRequest { path, files };
+    match client
+        .post(format!("{}/seed", url))
+        .json(&request)
+        .send()
+        .await
+    {
+        Ok(resp) if resp.status() == StatusCode::NOT_FOUND => Err(format!(
+            "the server at {} does not accept seeds, start it with --seed-dir",
+            url
+        )
+        .into()),
+        Ok(resp) => {
+            let served = resp.error_for_status()?.json::<SeedResponse>().await?;
+            if served.md5 == md5 {
+                eprintln!("{} is seeded by {}", md5, url);
+            } else {
+                // files outside the tree were kept, or one changed since
+                eprintln!(
+                    "{} is seeded by {} as {}, it is no longer {}",
+                    root.display(),
+                    url,
+                    served.md5,
+                    md5
+                );
+            }
+            Ok(())
+        }
+        Err(err) if err.is_connect() => {
+            eprintln!("No server at {}, starting one to seed {}", url, md5);
+            let mut known = HashCache::new();
+            for (stamp, md5) in request.files {
+                known.insert(stamp, md5);
+            }
+            if options.hash.is_none() {
+                options.hash = Some(HashAlgo::of_id(md5)?);
+            }
+            startup(
+                CreateArgs::Seed {
+                    path: request.path,
+                    known,
+                },
+                options,
+            )
+            .await?;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,435 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::planer::Action;
+
+/// Suffix of a version that is still being downloaded.
+const STAGING_SUFFIX: &str = ".staging";
+
+/// `.{name}{suffix}` next to `dest`.
+fn sibling(dest: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let name = dest.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no file name", dest.display()),
+        )
+    })?;
+    let mut sibling = OsString::from(".");
+    sibling.push(name);
+    sibling.push(suffix);
+    Ok(dest.with_file_name(sibling))
+}
+
+/// Where a download is staged before it replaces `dest`, next to it so the
+/// switch is a rename on the same file system.
+pub fn staging_path(dest: &Path) -> io::Result<PathBuf> {
+    sibling(dest, ".p2psync-staging")
+}
+
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) => std::fs::File::open(parent)?.sync_all(),
+        None => std::fs::File::open(".")?.sync_all(),
+    }
+}
+
+/// Swaps the entries at `a` and `b` with one `renameat2(RENAME_EXCHANGE)`.
+/// Where the kernel or the file system does not support it, falls back to
+/// `exchange_by_rename`.
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    match renameat2_exchange(a, b) {
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {}
+        result => return result,
+    }
+    exchange_by_rename(a, b)
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2_exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Swaps the entries at `a` and `b` by renaming `b` aside and `a` to it.
+/// Readers of `b` find nothing between the two renames.
+fn exchange_by_rename(a: &Path, b: &Path) -> io::Result<()> {
+    let aside = sibling(b, ".p2psync-old")?;
+    std::fs::rename(b, &aside)?;
+    if let Err(err) = std::fs::rename(a, b) {
+        std::fs::rename(&aside, b)?;
+        return Err(err);
+    }
+    std::fs::rename(&aside, a)
+}
+
+/// Moves the tree at `staging` to `dest`. An existing `dest` is swapped out
+/// in a single step, so readers see either the old or the new tree, and is
+/// removed afterwards.
+pub fn replace(staging: &Path, dest: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(dest) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => std::fs::rename(staging, dest)?,
+        Err(err) => return Err(err),
+        Ok(meta) => {
+            // a file replaces a file with a plain rename
+            if meta.is_file() && std::fs::symlink_metadata(staging)?.is_file() {
+                std::fs::rename(staging, dest)?;
+            } else {
+                exchange(staging, dest)?;
+                if meta.is_dir() {
+                    std::fs::remove_dir_all(staging)?;
+                } else {
+                    std::fs::remove_file(staging)?;
+                }
+            }
+        }
+    }
+    sync_parent(dest)
+}
+
+/// Whether `path` exists as what `root`, the first action of a plan, makes
+/// of it: a directory or a file.
+fn same_kind(path: &Path, root: &Action) -> io::Result<bool> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    Ok(match root {
+        Action::MakeDir { .. } => meta.is_dir(),
+        Action::Download { .. } => meta.is_file(),
+        _ => false,
+    })
+}
+
+/// Gets `staging` ready for the plan starting at `root`. A leftover of a
+/// failed download is kept so it resumes, unless it is of another kind than
+/// the plan's root. The files of `from` are then linked in if `from` is of
+/// the same kind.
+pub fn prepare(staging: &Path, from: Option<&Path>, root: &Action) -> io::Result<()> {
+    match std::fs::symlink_metadata(staging) {
+        Ok(meta) if !same_kind(staging, root)? => {
+            if meta.is_dir() {
+                std::fs::remove_dir_all(staging)?;
+            } else {
+                std::fs::remove_file(staging)?;
+            }
+        }
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    match from {
+        Some(from) if same_kind(from, root)? => link_missing(from, staging),
+        _ => Ok(()),
+    }
+}
+
+/// Hard links every file below `from` that is missing below `to`, creating
+/// directories as needed. Downloads replace files by renaming over them, so
+/// changing the tree at `to` never changes the one at `from`.
+fn link_missing(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = std::fs::symlink_metadata(from)?;
+    if meta.is_file() {
+        return match std::fs::hard_link(from, to) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            result => result,
+        };
+    }
+    if !meta.is_dir() {
+        return Ok(());
+    }
+    match std::fs::create_dir(to) {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            if !std::fs::symlink_metadata(to)?.is_dir() {
+                return Ok(());
+            }
+        }
+        result => result?,
+    }
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        link_missing(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// Moves every path of `actions` from below `from` to below `to`.
+pub fn rebase(actions: Vec<Action>, from: &Path, to: &Path) -> Vec<Action> {
+    let rebase_path = |path: PathBuf| match path.strip_prefix(from) {
+        Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+        Ok(rest) => to.join(rest),
+        Err(_) => path,
+    };
+    actions
+        .into_iter()
+        .map(|action| match action {
+            Action::Download {
+                peers,
+                peer_id,
+                path,
+                md5,
+                size,
+            } => Action::Download {
+                peers,
+                peer_id,
+                path: rebase_path(path),
+                md5,
+                size,
+            },
+            Action::MakeDir { path } => Action::MakeDir {
+                path: rebase_path(path),
+            },
+            Action::Remove { path } => Action::Remove {
+                path: rebase_path(path),
+            },
+            Action::RemoveDir { path } => Action::RemoveDir {
+                path: rebase_path(path),
+            },
+        })
+        .collect()
+}
+
+/// A destination that is a symlink to the current version. The versions
+/// live in `.{name}.p2psync/versions` next to it, on the same file system so
+/// they can share files through hard links.
+pub struct Versions {
+    dest: PathBuf,
+    // relative to the directory of `dest`, so the link survives moving it
+    versions_link: PathBuf,
+    versions: PathBuf,
+    pub hash_cache: PathBuf,
+}
+
+impl Versions {
+    pub fn of(dest: &Path) -> io::Result<Self> {
+        let state = sibling(dest, ".p2psync")?;
+        let state_name = PathBuf::from(state.file_name().unwrap());
+        Ok(Versions {
+            dest: dest.to_path_buf(),
+            versions_link: state_name.join("versions"),
+            versions: state.join("versions"),
+            hash_cache: state.join("hash.cache"),
+        })
+    }
+
+    /// The id `dest` points to, `None` before the first version. Fails if
+    /// `dest` is something else than a symlink.
+    pub fn current(&self) -> io::Result<Option<String>> {
+        match std::fs::symlink_metadata(&self.dest) {
+            Ok(meta) if meta.file_type().is_symlink() => {}
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} exists and is not a symlink, move it out of the way",
+                        self.dest.display()
+                    ),
+                ));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let target = std::fs::read_link(&self.dest)?;
+        Ok(target
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()))
+    }
+
+    pub fn create_dir(&self) -> io::Result<()> {
+        std::fs::create_dir_all(&self.versions)
+    }
+
+    pub fn version(&self, md5: &str) -> PathBuf {
+        self.versions.join(md5)
+    }
+
+    pub fn staging(&self, md5: &str) -> PathBuf {
+        self.versions.join(format!("{}{}", md5, STAGING_SUFFIX))
+    }
+
+    /// Points `dest` at version `md5` with a single rename, so readers see
+    /// either the old or the new version.
+    pub fn switch(&self, md5: &str) -> io::Result<()> {
+        let mut tmp = self.dest.clone().into_os_string();
+        tmp.push(".p2psync-switch");
+        let tmp = PathBuf::from(tmp);
+        match std::fs::remove_file(&tmp) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        std::os::unix::fs::symlink(self.versions_link.join(md5), &tmp)?;
+        std::fs::rename(&tmp, &self.dest)?;
+        sync_parent(&self.dest)
+    }
+
+    /// Removes every version and staging directory but the ones in `keep`.
+    pub fn prune(&self, keep: &[&str]) -> io::Result<()> {
+        for entry in std::fs::read_dir(&self.versions)? {
+            let entry = entry?;
+            if keep.iter().any(|md5| entry.file_name() == *md5) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                std::fs::remove_dir_all(entry.path())?;
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn test_replace() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("model");
+        let staging = staging_path(&dest)?;
+        assert_eq!(staging, dir.path().join(".model.p2psync-staging"));
+
+        // the first tree is moved into place
+        std::fs::create_dir_all(staging.join("sub"))?;
+        std::fs::write(staging.join("sub/a"), "a")?;
+        std::fs::write(staging.join("b"), "b")?;
+        replace(&staging, &dest)?;
+        assert!(!staging.exists());
+
+        // the next one starts as hard links and swaps the old tree out
+        let root = Action::MakeDir { path: dest.clone() };
+        prepare(&staging, Some(&dest), &root)?;
+        std::fs::write(staging.join("b.tmp"), "new b")?;
+        std::fs::rename(staging.join("b.tmp"), staging.join("b"))?;
+        assert_eq!(std::fs::read_to_string(dest.join("b"))?, "b");
+        replace(&staging, &dest)?;
+        assert!(!staging.exists());
+        assert_eq!(std::fs::read_to_string(dest.join("b"))?, "new b");
+        assert_eq!(std::fs::read_to_string(dest.join("sub/a"))?, "a");
+
+        // a leftover of another kind is cleared, a file replaces a directory
+        std::fs::create_dir(&staging)?;
+        let file = Action::Download {
+            peers: Arc::new(RwLock::new(Vec::new())),
+            peer_id: 0,
+            path: dest.clone(),
+            md5: String::new(),
+            size: 4,
+        };
+        prepare(&staging, Some(&dest), &file)?;
+        assert!(!staging.exists());
+        std::fs::write(&staging, "file")?;
+        replace(&staging, &dest)?;
+        assert_eq!(std::fs::read_to_string(&dest)?, "file");
+        Ok(())
+    }
+
+    #[test]
+    fn test_exchange_by_rename() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        std::fs::create_dir(&a)?;
+        std::fs::write(a.join("x"), "new")?;
+        std::fs::write(&b, "old")?;
+        exchange_by_rename(&a, &b)?;
+        assert_eq!(std::fs::read_to_string(b.join("x"))?, "new");
+        assert_eq!(std::fs::read_to_string(&a)?, "old");
+        assert!(!dir.path().join(".b.p2psync-old").exists());
+
+        // a failed swap puts `b` back
+        assert!(exchange_by_rename(&dir.path().join("missing"), &b).is_err());
+        assert_eq!(std::fs::read_to_string(b.join("x"))?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase() {
+        let actions = vec![
+            Action::MakeDir {
+                path: PathBuf::from("./model"),
+            },
+            Action::Remove {
+                path: PathBuf::from("./model/old"),
+            },
+        ];
+        let paths = rebase(actions, Path::new("./model"), Path::new("./.model.staging"))
+            .into_iter()
+            .map(|action| match action {
+                Action::MakeDir { path } | Action::Remove { path } => path,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("./.model.staging"),
+                PathBuf::from("./.model.staging/old")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_switch_versions() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let versions = Versions::of(&dir.path().join("model"))?;
+        versions.create_dir()?;
+        assert_eq!(versions.current()?, None);
+
+        let v1 = versions.version("v1");
+        std::fs::create_dir_all(v1.join("sub"))?;
+        std::fs::write(v1.join("sub/a"), "a")?;
+        versions.switch("v1")?;
+        assert_eq!(versions.current()?.as_deref(), Some("v1"));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("model/sub/a"))?,
+            "a"
+        );
+
+        let root = Action::MakeDir {
+            path: dir.path().join("model"),
+        };
+        prepare(&versions.staging("v2"), Some(&v1), &root)?;
+        std::fs::rename(versions.staging("v2"), versions.version("v2"))?;
+        versions.switch("v2")?;
+        assert_eq!(versions.current()?.as_deref(), Some("v2"));
+
+        // the previous version is kept, older ones and leftovers are not
+        std::fs::create_dir(versions.staging("v3"))?;
+        versions.prune(&["v2", "v1"])?;
+        assert!(v1.exists() && !versions.staging("v3").exists());
+        versions.prune(&["v2"])?;
+        assert!(!v1.exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("model/sub/a"))?,
+            "a"
+        );
+
+        // a real directory at dest is never replaced
+        std::fs::create_dir(dir.path().join("plain"))?;
+        assert!(Versions::of(&dir.path().join("plain"))?.current().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,503 @@
+use reqwest::{Client, StatusCode, header};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::downloader::partial::PartialDownload;
+use crate::hash::HashAlgo;
+use crate::server::hash_file;
+
+/// Files at least this large are fetched piece by piece from every peer.
+pub const SWARM_THRESHOLD: usize = 64 * 1024 * 1024;
+
+/// Size of a piece, each piece is fetched with one HTTP range request.
+pub const PIECE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Concurrent piece requests sent to a single peer.
+const REQUESTS_PER_PEER: usize = 2;
+
+/// A peer is given up after this many failed pieces.
+const MAX_PEER_FAILURES: usize = 3;
+
+type BoxError = Box<dyn Error + Sync + Send>;
+
+struct Pieces {
+    partial: Mutex<PartialDownload>,
+    pending: Mutex<VecDeque<u64>>,
+    // pieces not written yet, including the ones in flight
+    remaining: AtomicUsize,
+    size: u64,
+    piece_size: u64,
+}
+
+impl Pieces {
+    fn range(&self, piece: u64) -> (u64, u64) {
+        let start = piece * self.piece_size;
+        (start, (start + self.piece_size).min(self.size))
+    }
+}
+
+/// The first byte, last byte and file size of a `Content-Range` header.
+fn parse_content_range(value: &str) -> Option<(u64, u64, u64)> {
+    let (range, size) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.parse().ok()?, last.parse().ok()?, size.parse().ok()?))
+}
+
+async fn fetch_piece(
+    client: &Client,
+    url: &str,
+    file: &Arc<std::fs::File>,
+    pieces: &Pieces,
+    piece: u64,
+    pbar: &Mutex<tqdm::Tqdm<()>>,
+) -> Result<(), BoxError> {
+    let (start, end) = pieces.range(piece);
+    let resp = client
+        .get(url)
+        .header(header::RANGE, format!("bytes={}-{}", start, end - 1))
+        .send()
+        .await?
+        .error_for_status()?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(format!("{} does not support range requests", url).into());
+    }
+    // a range of the right length at another offset would corrupt the file
+    let content_range = resp
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_content_range);
+    if content_range != Some((start, end - 1, pieces.size)) {
+        return Err(format!(
+            "{} answered bytes {}-{} with Content-Range {:?}",
+            url,
+            start,
+            end - 1,
+            resp.headers().get(header::CONTENT_RANGE)
+        )
+        .into());
+    }
+
+    let data = resp.bytes().await?;
+    if data.len() as u64 != end - start {
+        return Err(format!(
+            "{} returned {} bytes for a {} byte piece",
+            url,
+            data.len(),
+            end - start
+        )
+        .into());
+    }
+
+    let len = data.len();
+    let file = file.clone();
+    tokio::task::spawn_blocking(move || {
+        file.write_all_at(&data, start)?;
+        file.sync_data()
+    })
+    .await??;
+    pieces.partial.lock().unwrap().mark_done(start, end)?;
+    pbar.lock().unwrap().update(len)?;
+    Ok(())
+}
+
+/// Takes pieces from the shared queue and fetches them from one peer until no
+/// piece is left, or until the peer failed `MAX_PEER_FAILURES` pieces, however
+/// many it fetched in between. Failed pieces go back to the queue for the
+/// other peers.
+async fn fetch_pieces(
+    client: &Client,
+    url: &str,
+    file: &Arc<std::fs::File>,
+    pieces: &Pieces,
+    failures: &AtomicUsize,
+    pbar: &Mutex<tqdm::Tqdm<()>>,
+) -> Result<(), BoxError> {
+    loop {
+        if failures.load(Ordering::SeqCst) >= MAX_PEER_FAILURES {
+            return Err(format!("{} failed {} times, giving up", url, MAX_PEER_FAILURES).into());
+        }
+
+        let piece = pieces.pending.lock().unwrap().pop_front();
+        let piece = match piece {
+            Some(piece) => piece,
+            None if pieces.remaining.load(Ordering::SeqCst) == 0 => return Ok(()),
+            None => {
+                // another worker may still fail and hand its piece back
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+
+        match fetch_piece(client, url, file, pieces, piece, pbar).await {
+            Ok(()) => {
+                pieces.remaining.fetch_sub(1, Ordering::SeqCst);
+            }
+            Err(err) => {
+                eprintln!("piece {} from {}: {}", piece, url, err);
+                failures.fetch_add(1, Ordering::SeqCst);
+                pieces.pending.lock().unwrap().push_back(piece);
+            }
+        }
+    }
+}
+
+/// Downloads a `size` byte file by splitting it into `piece_size` pieces that
+/// are fetched in parallel from all `peers` and written at their offsets into
+/// a preallocated part file. Pieces recorded by an interrupted earlier attempt
+/// are skipped. The md5 of the whole file is checked at the end.
+pub async fn swarm_download(
+    client: &Client,
+    md5: &str,
+    peers: &[String],
+    file_path: &Path,
+    size: u64,
+    piece_size: u64,
+    pbar: Arc<Mutex<tqdm::Tqdm<()>>>,
+) -> Result<(), BoxError> {
+    let (partial, file) = PartialDownload::open(file_path, md5, size)?;
+    file.set_len(size)?;
+    let file = Arc::new(file);
+
+    let mut pieces = Pieces {
+        partial: Mutex::new(partial),
+        pending: Mutex::new(VecDeque::new()),
+        remaining: AtomicUsize::new(0),
+        size,
+        piece_size,
+    };
+    let mut resumed = 0;
+    for piece in 0..size.div_ceil(piece_size) {
+        let (start, end) = pieces.range(piece);
+        if pieces.partial.get_mut().unwrap().contains(start, end) {
+            resumed += end - start;
+        } else {
+            pieces.pending.get_mut().unwrap().push_back(piece);
+        }
+    }
+    pbar.lock().unwrap().update(resumed as usize)?;
+    *pieces.remaining.get_mut() = pieces.pending.get_mut().unwrap().len();
+
+    let urls = peers
+        .iter()
+        .map(|peer| format!("{}/download?md5={}", peer, md5))
+        .collect::<Vec<_>>();
+    let failures = peers
+        .iter()
+        .map(|_| AtomicUsize::new(0))
+        .collect::<Vec<_>>();
+    let workers = urls
+        .iter()
+        .zip(failures.iter())
+        .flat_map(|(url, failures)| {
+            (0..REQUESTS_PER_PEER)
+                .map(|_| fetch_pieces(client, url, &file, &pieces, failures, pbar.as_ref()))
+                .collect::<Vec<_>>()
+        });
+    let errs = futures::future::join_all(workers)
+        .await
+        .into_iter()
+        .filter_map(|result| result.err().map(|e| e.to_string()))
+        .collect::<Vec<_>>();
+
+    if pieces.remaining.load(Ordering::SeqCst) != 0 {
+        return Err(format!("All peers failed: {}", errs.join("; ")).into());
+    }
+
+    file.sync_all()?;
+    let partial = pieces.partial.into_inner().unwrap();
+    let path = partial.part_path().to_path_buf();
+    let algo = HashAlgo::of_id(md5)?;
+    let actual = tokio::task::spawn_blocking(move || hash_file(path.as_path(), algo)).await??;
+    if md5 != actual {
+        partial.discard()?;
+        Err(format!("{} mismatch", algo).into())
+    } else {
+        Ok(partial.finish()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        body::Body,
+        http::{HeaderMap, StatusCode},
+        response::{IntoResponse, Response},
+        routing::get,
+    };
+    use tokio::net::TcpListener;
+
+    fn test_data() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn requested_range(headers: &HeaderMap) -> (usize, usize) {
+        let range = headers[header::RANGE].to_str().unwrap();
+        let (start, end) = range
+            .strip_prefix("bytes=")
+            .and_then(|r| r.split_once('-'))
+            .unwrap();
+        (start.parse().unwrap(), end.parse().unwrap())
+    }
+
+    /// Bytes `start..=end` of the test data, labelled as `label_start..`.
+    fn piece_response(start: usize, end: usize, label_start: usize) -> Response {
+        let data = test_data();
+        let end = end.min(data.len() - 1);
+        let content_range = format!(
+            "bytes {}-{}/{}",
+            label_start,
+            label_start + end - start,
+            data.len()
+        );
+        (
+            StatusCode::PARTIAL_CONTENT,
+            [(header::CONTENT_RANGE, content_range)],
+            Body::from(data[start..=end].to_vec()),
+        )
+            .into_response()
+    }
+
+    async fn serve_piece(headers: HeaderMap) -> Response {
+        let (start, end) = requested_range(&headers);
+        piece_response(start, end, start)
+    }
+
+    async fn start_peer(broken: bool) -> String {
+        let app = if broken {
+            Router::new().route(
+                "/download",
+                get(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+            )
+        } else {
+            Router::new().route("/download", get(serve_piece))
+        };
+        serve(app).await
+    }
+
+    /// A peer that records the start of every range it is asked for.
+    async fn start_recording_peer() -> (String, Arc<Mutex<Vec<usize>>>) {
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let recorded = starts.clone();
+        let app = Router::new().route(
+            "/download",
+            get(move |headers: HeaderMap| {
+                recorded.lock().unwrap().push(requested_range(&headers).0);
+                serve_piece(headers)
+            }),
+        );
+        (serve(app).await, starts)
+    }
+
+    async fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_swarm_download_skips_broken_peer() {
+        let peers = vec![start_peer(false).await, start_peer(true).await];
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data = test_data();
+        let md5 = format!("{:x}", md5::compute(&data));
+
+        let pbar = Arc::new(Mutex::new(tqdm::pbar(Some(data.len()))));
+        swarm_download(
+            &Client::new(),
+            &md5,
+            &peers,
+            path.as_path(),
+            data.len() as u64,
+            1024,
+            pbar,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_swarm_download_resumes_recorded_pieces() {
+        let data = test_data();
+        let md5 = format!("{:x}", md5::compute(&data));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        {
+            let (mut partial, _) =
+                PartialDownload::open(path.as_path(), &md5, data.len() as u64).unwrap();
+            std::fs::write(partial.part_path(), &data[..4096]).unwrap();
+            partial.mark_done(0, 4096).unwrap();
+        }
+
+        // the first four pieces are on disk, the peer only serves the rest
+        let (peer, starts) = start_recording_peer().await;
+        let peers = vec![peer];
+        let pbar = Arc::new(Mutex::new(tqdm::pbar(Some(data.len()))));
+        swarm_download(
+            &Client::new(),
+            &md5,
+            &peers,
+            path.as_path(),
+            data.len() as u64,
+            1024,
+            pbar,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), data);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        let mut starts = starts.lock().unwrap().clone();
+        starts.sort();
+        assert_eq!(starts, (4096..10_000).step_by(1024).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_swarm_download_rejects_other_range() {
+        // a peer answering every piece with the bytes of another one
+        let app = Router::new().route(
+            "/download",
+            get(|headers: HeaderMap| async move {
+                let (start, end) = requested_range(&headers);
+                let shifted = (start + 1024) % (10_000 - (end - start));
+                piece_response(shifted, shifted + end - start, shifted)
+            }),
+        );
+        let peers = vec![serve(app).await];
+        let data = test_data();
+        let md5 = format!("{:x}", md5::compute(&data));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let pbar = Arc::new(Mutex::new(tqdm::pbar(Some(data.len()))));
+        let err = swarm_download(
+            &Client::new(),
+            &md5,
+            &peers,
+            path.as_path(),
+            data.len() as u64,
+            1024,
+            pbar,
+        )
+        .await
+        .unwrap_err();
+
+        // the pieces are refused before anything is written
+        assert!(err.to_string().starts_with("All peers failed"), "{}", err);
+        let (partial, _) = PartialDownload::open(path.as_path(), &md5, data.len() as u64).unwrap();
+        let part = std::fs::read(partial.part_path()).unwrap();
+        assert!(part.iter().all(|byte| *byte == 0));
+    }
+
+    #[tokio::test]
+    async fn test_swarm_download_drops_flaky_peer() {
+        // a peer failing every other piece
+        let served = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let (counted, failing) = (served.clone(), failed.clone());
+        let app = Router::new().route(
+            "/download",
+            get(move |headers: HeaderMap| {
+                let failed = counted.fetch_add(1, Ordering::SeqCst) % 2 == 1;
+                if failed {
+                    failing.fetch_add(1, Ordering::SeqCst);
+                }
+                async move {
+                    if failed {
+                        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    } else {
+                        serve_piece(headers).await
+                    }
+                }
+            }),
+        );
+        let peers = vec![serve(app).await, start_peer(false).await];
+        let data = test_data();
+        let md5 = format!("{:x}", md5::compute(&data));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let pbar = Arc::new(Mutex::new(tqdm::pbar(Some(data.len()))));
+        swarm_download(
+            &Client::new(),
+            &md5,
+            &peers,
+            path.as_path(),
+            data.len() as u64,
+            100,
+            pbar,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(std::fs::read(path).unwrap(), data);
+        // successes in between do not keep it in the swarm
+        let failed = failed.load(Ordering::SeqCst);
+        assert!(
+            failed < MAX_PEER_FAILURES + REQUESTS_PER_PEER,
+            "{} failures",
+            failed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_swarm_download_all_peers_broken() {
+        let peers = vec![start_peer(true).await];
+        let dir = tempfile::tempdir().unwrap();
+        let pbar = Arc::new(Mutex::new(tqdm::pbar(Some(10_000))));
+        let result = swarm_download(
+            &Client::new(),
+            "md5",
+            &peers,
+            dir.path().join("data.bin").as_path(),
+            10_000,
+            1024,
+            pbar,
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_swarm_download_verifies_with_id_algorithm() {
+        let peers = vec![start_peer(false).await];
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data = test_data();
+        let size = data.len();
+        let download = |id: String| {
+            let (peers, path) = (peers.clone(), path.clone());
+            async move {
+                let pbar = Arc::new(Mutex::new(tqdm::pbar(Some(size))));
+                swarm_download(
+                    &Client::new(),
+                    &id,
+                    &peers,
+                    path.as_path(),
+                    size as u64,
+                    1024,
+                    pbar,
+                )
+                .await
+            }
+        };
+
+        download(HashAlgo::Blake3.id_of(&data)).await.unwrap();
+        assert_eq!(std::fs::read(path.as_path()).unwrap(), data);
+
+        let err = download(HashAlgo::Sha256.id_of(b"other"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "sha256 mismatch");
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Algorithm behind a content id. Ids are the lowercase hex digest prefixed
+/// with the algorithm, like `sha256:9f86...`, so ids of different algorithms
+/// can coexist in one cluster. md5 ids have no prefix, so they stay the same
+/// as before other algorithms were supported.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgo {
+    #[default]
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Md5 => "md5",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+
+    fn hex_len(self) -> usize {
+        match self {
+            HashAlgo::Md5 => 32,
+            HashAlgo::Sha256 | HashAlgo::Blake3 => 64,
+        }
+    }
+
+    /// The algorithm of a content id, from its prefix.
+    pub fn of_id(id: &str) -> io::Result<Self> {
+        let (algo, hex) = match id.split_once(':') {
+            Some((prefix, hex)) => (prefix.parse::<HashAlgo>()?, hex),
+            None => (HashAlgo::Md5, id),
+        };
+        if algo == HashAlgo::Md5 && id.contains(':') {
+            // md5 ids are never prefixed, so each content has a single id
+            return Err(invalid_id(id));
+        }
+        if hex.len() != algo.hex_len() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid_id(id));
+        }
+        Ok(algo)
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgo::Md5 => Hasher::Md5(md5::Context::new()),
+            HashAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// The content id of `data`.
+    pub fn id_of(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+}
+
+fn invalid_id(id: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid content id {:?}", id),
+    )
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(HashAlgo::Md5),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown hash algorithm {:?}, use md5, sha256 or blake3", s),
+            )),
+        }
+    }
+}
+
+/// Incremental hasher that produces a content id.
+pub enum Hasher {
+    Md5(md5::Context),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(ctx) => ctx.consume(data),
+            Hasher::Sha256(ctx) => ctx.update(data),
+            Hasher::Blake3(ctx) => {
+                ctx.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Md5(ctx) => format!("{:x}", ctx.compute()),
+            Hasher::Sha256(ctx) => format!("sha256:{:x}", ctx.finalize()),
+            Hasher::Blake3(ctx) => format!("blake3:{}", ctx.finalize().to_hex()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HashAlgo;
+
+    #[test]
+    fn test_ids_round_trip() {
+        for algo in [HashAlgo::Md5, HashAlgo::Sha256, HashAlgo::Blake3] {
+            let id = algo.id_of(b"hello");
+            assert_eq!(HashAlgo::of_id(&id).unwrap(), algo);
+            assert_eq!(algo.name().parse::<HashAlgo>().unwrap(), algo);
+        }
+        assert_eq!(
+            HashAlgo::Md5.id_of(b"hello"),
+            format!("{:x}", md5::compute(b"hello"))
+        );
+        assert_eq!(
+            HashAlgo::Sha256.id_of(b"hello"),
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
+    #[test]
+    fn test_invalid_ids() {
+        for id in [
+            "",
+            "md5:5d41402abc4b2a76b9719d911017c592",
+            "sha256:5d41402abc4b2a76b9719d911017c592",
+            "sha1:aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d",
+            "not hex at all, but 32 chars....",
+        ] {
+            assert!(HashAlgo::of_id(id).is_err(), "{:?}", id);
+        }
+    }
+}
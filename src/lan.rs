@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Multicast group servers announce themselves to on the local network.
+pub const LAN_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 77), 7677);
+
+/// How often a server announces itself, shorter than the window a
+/// downloader listens for.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Ids sent in one packet, so a packet fits in a single ethernet frame even
+/// with sha256 ids. Servers with more ids send several packets.
+const IDS_PER_PACKET: usize = 16;
+
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LanAnnounce {
+    pub addr: String,
+    pub md5s: Vec<String>,
+}
+
+/// Sends the announcements of `self_url` with the ids in `md5s` to `group`
+/// through `interface` every `interval`, and right away when the ids change.
+pub fn spawn_announcer(
+    group: SocketAddrV4,
+    interface: Ipv4Addr,
+    self_url: String,
+    mut md5s: watch::Receiver<Vec<String>>,
+    interval: Duration,
+) -> io::Result<JoinHandle<()>> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.bind(&SocketAddr::from((interface, 0)).into())?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let md5s_now = md5s.borrow_and_update().clone();
+            let packets = if md5s_now.is_empty() {
+                vec![Vec::new()]
+            } else {
+                md5s_now
+                    .chunks(IDS_PER_PACKET)
+                    .map(|ids| ids.to_vec())
+                    .collect()
+            };
+            for ids in packets {
+                let packet = serde_json::to_vec(&LanAnnounce {
+                    addr: self_url.clone(),
+                    md5s: ids,
+                })
+                .unwrap();
+                if let Err(err) = socket.send_to(&packet, group).await {
+                    eprintln!("Failed to send lan announce: {:?}", err);
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                Ok(()) = md5s.changed() => {}
+            }
+        }
+    }))
+}
+
+/// Listens on `group` through `interface` for `window` and returns every
+/// server heard, with the ids it announced.
+pub async fn discover(
+    group: SocketAddrV4,
+    interface: Ipv4Addr,
+    window: Duration,
+) -> io::Result<HashMap<String, Vec<String>>> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // several downloaders on one host share the port
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+
+    let mut peers = HashMap::<String, Vec<String>>::new();
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let deadline = tokio::time::Instant::now() + window;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, _) = received?;
+        // packets of other programs on the group are ignored
+        if let Ok(announce) = serde_json::from_slice::<LanAnnounce>(&buf[..len]) {
+            let md5s = peers.entry(announce.addr).or_default();
+            for md5 in announce.md5s {
+                if !md5s.contains(&md5) {
+                    md5s.push(md5);
+                }
+            }
+        }
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_discover() {
+        let group = SocketAddrV4::new(*LAN_GROUP.ip(), 17677);
+        let interface = Ipv4Addr::LOCALHOST;
+        let ids = (0..40).map(|i| format!("{:032x}", i)).collect::<Vec<_>>();
+        let (_tx, rx) = watch::channel(ids.clone());
+        let (_empty_tx, empty_rx) = watch::channel(Vec::new());
+        let announcers = [
+            spawn_announcer(
+                group,
+                interface,
+                "http://127.0.0.1:1".to_string(),
+                rx,
+                Duration::from_millis(100),
+            )
+            .unwrap(),
+            spawn_announcer(
+                group,
+                interface,
+                "http://127.0.0.1:2".to_string(),
+                empty_rx,
+                Duration::from_millis(100),
+            )
+            .unwrap(),
+        ];
+
+        let peers = discover(group, interface, Duration::from_millis(500))
+            .await
+            .unwrap();
+        for announcer in announcers {
+            announcer.abort();
+        }
+
+        assert_eq!(peers.len(), 2);
+        let mut announced = peers["http://127.0.0.1:1"].clone();
+        announced.sort();
+        assert_eq!(announced, ids);
+        assert!(peers["http://127.0.0.1:2"].is_empty());
+    }
+}
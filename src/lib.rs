@@ -1,4 +1,10 @@
+pub mod auth;
+pub mod dht;
 pub mod downloader;
+pub mod hash;
+pub mod lan;
+pub mod manifest;
 pub mod server;
+pub mod tls;
 pub mod tracker;
 pub mod utils;
@@ -1,13 +1,14 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-mod tracker;
-use tracker::TrackerServer;
-
-mod server;
-use server::{CreateArgs, startup};
-
-mod downloader;
-use downloader::download;
+use p2psync::auth::{Credentials, load_token};
+use p2psync::downloader::{DownloadOptions, FollowOptions, download, follow};
+use p2psync::hash::HashAlgo;
+use p2psync::manifest::{PublicKey, SigningKey};
+use p2psync::server::{CreateArgs, ServeOptions, startup};
+use p2psync::tls::ServerTls;
+use p2psync::tracker::{self, TrackerServer};
 
 #[derive(Parser)]
 #[command(name = "p2psync")]
@@ -18,11 +19,99 @@ struct Cli {
     command: Option<Commands>,
 }
 
+// Shared secret and certificates presented to trackers and servers.
+#[derive(Args)]
+struct ClientArgs {
+    #[arg(
+        long,
+        help = "shared secret of the cluster",
+        conflicts_with = "token_file"
+    )]
+    token: Option<String>,
+    #[arg(
+        long,
+        help = "file whose first line is the shared secret of the cluster"
+    )]
+    token_file: Option<String>,
+    #[arg(long, help = "CA certificate that trackers and servers are signed by")]
+    ca: Option<String>,
+    #[arg(
+        long,
+        requires = "client_key",
+        help = "certificate presented to servers that require client certificates"
+    )]
+    client_cert: Option<String>,
+    #[arg(long, requires = "client_cert", help = "PKCS#8 key of --client-cert")]
+    client_key: Option<String>,
+}
+
+impl ClientArgs {
+    fn credentials(self) -> std::io::Result<Credentials> {
+        let token = load_token(self.token, self.token_file.as_deref().map(Path::new))?;
+        let identity = self
+            .client_cert
+            .as_deref()
+            .zip(self.client_key.as_deref())
+            .map(|(cert, key)| (Path::new(cert), Path::new(key)));
+        Credentials::new(token).with_tls(self.ca.as_deref().map(Path::new), identity)
+    }
+}
+
+// Certificate a tracker or server is served with.
+#[derive(Args)]
+struct TlsArgs {
+    #[arg(long, requires = "tls_key", help = "certificate to serve over TLS")]
+    tls_cert: Option<String>,
+    #[arg(long, requires = "tls_cert", help = "key of --tls-cert")]
+    tls_key: Option<String>,
+    #[arg(
+        long,
+        requires = "tls_cert",
+        help = "CA that client certificates must be signed by"
+    )]
+    tls_client_ca: Option<String>,
+}
+
+impl TlsArgs {
+    fn server_tls(self) -> Option<ServerTls> {
+        Some(ServerTls {
+            cert: PathBuf::from(self.tls_cert?),
+            key: PathBuf::from(self.tls_key?),
+            client_ca: self.tls_client_ca.map(PathBuf::from),
+        })
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Tracker {
         #[arg(short, long, default_value_t = 9090)]
         port: u16,
+        #[arg(long, help = "file that keeps the known peers across restarts")]
+        state: Option<String>,
+        #[arg(long, help = "address of another tracker to replicate peers with")]
+        sibling: Vec<String>,
+        #[arg(
+            long,
+            help = "seconds between removals of silent peers",
+            default_value_t = 30
+        )]
+        cleanup_interval: u64,
+        #[arg(
+            long,
+            help = "seconds a peer may go without announcing before it is removed",
+            default_value_t = 300
+        )]
+        peer_timeout: u64,
+        #[arg(
+            long,
+            help = "seconds between probes of every peer's /health, off if unset"
+        )]
+        health_check_interval: Option<u64>,
+        #[command(flatten)]
+        client: ClientArgs,
+        #[command(flatten)]
+        tls: TlsArgs,
     },
     Serve {
         #[arg(long, help = "Directory to monitor")]
@@ -37,14 +126,174 @@ enum Commands {
         load_path: Option<String>,
         #[arg(short, long, help = "tracker address")]
         tracker: Vec<String>,
+        #[arg(long, help = "do not re-hash served directories when they change")]
+        no_watch: bool,
+        #[arg(long, help = "file that caches md5s across restarts")]
+        hash_cache: Option<String>,
+        #[arg(
+            long,
+            help = "content hash: md5 (default), sha256 or blake3, a loaded spec keeps its own"
+        )]
+        hash: Option<HashAlgo>,
+        #[arg(
+            long,
+            help = "announce every file and directory id to the trackers, not only roots"
+        )]
+        announce_all: bool,
+        #[arg(long, help = "join the DHT and publish the announced ids there")]
+        dht: bool,
+        #[arg(long, help = "DHT node to join through, implies --dht")]
+        dht_bootstrap: Vec<String>,
+        #[arg(long, help = "multicast the announced ids on the local network")]
+        lan: bool,
+        #[arg(
+            long,
+            help = "seconds to let transfers finish on Ctrl-C or SIGTERM",
+            default_value_t = 30
+        )]
+        drain_timeout: u64,
+        #[arg(long, help = "publisher key to sign the manifests of served ids with")]
+        sign_key: Option<String>,
+        #[arg(long, help = "signed manifest to serve, for content signed elsewhere")]
+        manifest: Vec<String>,
+        #[arg(
+            long,
+            help = "tag to point at the served root on the trackers, moved again on SIGHUP"
+        )]
+        tag: Option<String>,
+        #[arg(
+            long,
+            help = "accept POST /seed from this host for trees inside this directory, needs --token"
+        )]
+        seed_dir: Option<String>,
+        #[command(flatten)]
+        client: ClientArgs,
+        #[command(flatten)]
+        tls: TlsArgs,
+    },
+    /// Prints the id a tag points to, points it at a new id, or prints its
+    /// history.
+    Tag {
+        #[arg(help = "name of the tag, such as llama-70b:latest")]
+        name: String,
+        #[arg(long, help = "point the tag at this id")]
+        md5: Option<String>,
+        #[arg(
+            long,
+            conflicts_with = "md5",
+            help = "print every id the tag pointed to, oldest first"
+        )]
+        history: bool,
+        #[arg(short, long, required = true, help = "tracker address")]
+        tracker: Vec<String>,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Keeps a symlink pointed at a local copy of the version a tag points
+    /// to, downloading only the files that changed whenever the tag moves.
+    Follow {
+        #[arg(long, help = "tag to follow")]
+        tag: String,
+        #[arg(long, help = "symlink to the current version, created if missing")]
+        dest: String,
+        #[arg(short, long, required = true, help = "tracker address")]
+        tracker: Vec<String>,
+        #[arg(
+            long,
+            help = "seconds between checks of where the tag points",
+            default_value_t = 30
+        )]
+        interval: u64,
+        #[arg(short, long, help = "concurrency", default_value_t = 10)]
+        concurrency: usize,
+        #[arg(long, help = "DHT node to look up providers through")]
+        dht_bootstrap: Vec<String>,
+        #[arg(
+            long,
+            help = "file that caches md5s of local files, next to --dest by default"
+        )]
+        hash_cache: Option<String>,
+        #[arg(
+            long,
+            help = "public key of the publisher whose signed manifest the tree must match"
+        )]
+        trusted_key: Option<PublicKey>,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Generates a publisher key to sign manifests with.
+    Keygen {
+        #[arg(short, long, help = "file to write the private key to")]
+        out: String,
     },
     Download {
-        #[arg(short, long, help = "md5")]
-        md5: String,
+        #[arg(
+            short,
+            long,
+            required_unless_present = "tag",
+            conflicts_with = "tag",
+            help = "md5"
+        )]
+        md5: Option<String>,
+        #[arg(long, help = "tag to resolve to the id to download, on the trackers")]
+        tag: Option<String>,
         #[arg(short, long, help = "concurrency", default_value_t = 10)]
         concurrency: usize,
         #[arg(short, long, help = "tracker address")]
         tracker: Vec<String>,
+        #[arg(long, help = "DHT node to look up providers through")]
+        dht_bootstrap: Vec<String>,
+        #[arg(long, help = "discover servers multicasting on the local network")]
+        lan: bool,
+        #[arg(
+            long,
+            help = "seconds to listen for servers with --lan",
+            default_value_t = 3
+        )]
+        lan_wait: u64,
+        #[arg(long, help = "file that caches md5s of local files across runs")]
+        hash_cache: Option<String>,
+        #[arg(
+            long,
+            help = "path to write the downloaded root to, instead of ./{name}"
+        )]
+        dest: Option<String>,
+        #[arg(
+            long,
+            help = "keep each version next to the destination and make it a symlink to the current one"
+        )]
+        versioned: bool,
+        #[arg(long, help = "remove local files that are not in the downloaded tree")]
+        delete: bool,
+        #[arg(long, help = "print the plan without downloading or removing anything")]
+        dry_run: bool,
+        #[arg(long, help = "peer whose tree is trusted over the majority of peers")]
+        trusted_peer: Option<String>,
+        #[arg(
+            long,
+            help = "public key of the publisher whose signed manifest the tree must match"
+        )]
+        trusted_key: Option<PublicKey>,
+        #[arg(long, help = "fail if any peer serves a different tree")]
+        strict: bool,
+        #[arg(
+            long,
+            requires = "address",
+            help = "serve the tree once verified, from the server at --address or a new one"
+        )]
+        seed: bool,
+        #[arg(long, help = "address the seeding server listens on")]
+        address: Option<String>,
+        #[arg(
+            long,
+            help = "port the seeding server listens on",
+            default_value_t = 8080
+        )]
+        port: u16,
+        #[command(flatten)]
+        client: ClientArgs,
+        #[command(flatten)]
+        tls: TlsArgs,
     },
 }
 
@@ -53,9 +302,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let cli = Cli::parse();
 
     match cli.command {
-        Some(Commands::Tracker { port }) => {
+        Some(Commands::Tracker {
+            port,
+            state,
+            sibling,
+            cleanup_interval,
+            peer_timeout,
+            health_check_interval,
+            client,
+            tls,
+        }) => {
             println!("Starting tracker on port {}", port);
-            let server = TrackerServer::new();
+            let server = match state {
+                Some(path) => TrackerServer::with_state_path(PathBuf::from(path))?,
+                None => TrackerServer::new(),
+            }
+            .with_siblings(sibling)
+            .with_timeouts(
+                Duration::from_secs(cleanup_interval),
+                Duration::from_secs(peer_timeout),
+            )
+            .with_health_check(health_check_interval.map(Duration::from_secs))
+            .with_credentials(client.credentials()?)
+            .with_tls(tls.server_tls());
             server.start(port).await?;
         }
         Some(Commands::Serve {
@@ -65,7 +334,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             dump_path,
             load_path,
             tracker,
+            no_watch,
+            hash_cache,
+            hash,
+            announce_all,
+            dht,
+            dht_bootstrap,
+            lan,
+            drain_timeout,
+            sign_key,
+            manifest,
+            tag,
+            seed_dir,
+            client,
+            tls,
         }) => {
+            let options = ServeOptions {
+                address,
+                port,
+                dump_path,
+                tracker,
+                watch: !no_watch,
+                hash_cache: hash_cache.map(PathBuf::from),
+                hash,
+                announce_all,
+                dht: (dht || !dht_bootstrap.is_empty()).then_some(dht_bootstrap),
+                lan,
+                drain_timeout: Duration::from_secs(drain_timeout),
+                credentials: client.credentials()?,
+                tls: tls.server_tls(),
+                sign_key: sign_key.map(PathBuf::from),
+                manifests: manifest.into_iter().map(PathBuf::from).collect(),
+                tag,
+                seed_dir: seed_dir.map(PathBuf::from),
+            };
             startup(
                 if path.is_empty() {
                     match load_path {
@@ -77,20 +379,140 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 } else {
                     CreateArgs::Pathes(path)
                 },
-                address,
-                port,
-                dump_path,
-                tracker,
+                options,
             )
             .await?;
         }
+        Some(Commands::Tag {
+            name,
+            md5,
+            history,
+            tracker,
+            client,
+        }) => {
+            let client = client.credentials()?.client_builder().build()?;
+            if history {
+                let history = tracker::tag_history(&client, &tracker, &name).await?;
+                for version in history.versions {
+                    println!("{} {}", version.published_at, version.md5);
+                }
+            } else {
+                let tag = match md5 {
+                    Some(md5) => tracker::publish_tag(&client, &tracker, &name, &md5).await?,
+                    None => tracker::resolve_tag(&client, &tracker, &name).await?,
+                };
+                println!("{}", tag.md5);
+            }
+        }
+
+        Some(Commands::Follow {
+            tag,
+            dest,
+            tracker,
+            interval,
+            concurrency,
+            dht_bootstrap,
+            hash_cache,
+            trusted_key,
+            client,
+        }) => {
+            let options = FollowOptions {
+                tag,
+                dest: PathBuf::from(dest),
+                interval: Duration::from_secs(interval),
+                download: DownloadOptions {
+                    concurrency,
+                    tracker_urls: tracker,
+                    dht_bootstrap,
+                    lan_window: None,
+                    hash_cache: hash_cache.map(PathBuf::from),
+                    dest: None,
+                    versioned: true,
+                    delete: true,
+                    dry_run: false,
+                    trusted_peer: None,
+                    trusted_key,
+                    strict: false,
+                    credentials: client.credentials()?,
+                    seed: None,
+                },
+            };
+            follow(options).await?;
+        }
+
+        Some(Commands::Keygen { out }) => {
+            println!("{}", SigningKey::generate(Path::new(&out))?);
+        }
 
         Some(Commands::Download {
             md5,
+            tag,
             concurrency,
             tracker,
+            dht_bootstrap,
+            lan,
+            lan_wait,
+            hash_cache,
+            dest,
+            versioned,
+            delete,
+            dry_run,
+            trusted_peer,
+            trusted_key,
+            strict,
+            seed,
+            address,
+            port,
+            client,
+            tls,
         }) => {
-            if let Err(err) = download(md5, concurrency, tracker).await {
+            let credentials = client.credentials()?;
+            let md5 = match (md5, tag) {
+                (Some(md5), _) => md5,
+                (None, Some(name)) => {
+                    let client = credentials.client_builder().build()?;
+                    let tag = tracker::resolve_tag(&client, &tracker, &name).await?;
+                    eprintln!("tag {} points to {}", name, tag.md5);
+                    tag.md5
+                }
+                (None, None) => unreachable!("clap requires --md5 or --tag"),
+            };
+            let seed = seed.then(|| ServeOptions {
+                address: address.unwrap_or_default(),
+                port,
+                dump_path: None,
+                tracker: tracker.clone(),
+                watch: true,
+                hash_cache: None,
+                hash: None,
+                announce_all: false,
+                dht: (!dht_bootstrap.is_empty()).then(|| dht_bootstrap.clone()),
+                lan,
+                drain_timeout: Duration::from_secs(30),
+                credentials: credentials.clone(),
+                tls: tls.server_tls(),
+                sign_key: None,
+                manifests: Vec::new(),
+                tag: None,
+                seed_dir: None,
+            });
+            let options = DownloadOptions {
+                concurrency,
+                tracker_urls: tracker,
+                dht_bootstrap,
+                lan_window: lan.then_some(Duration::from_secs(lan_wait)),
+                hash_cache: hash_cache.map(PathBuf::from),
+                dest: dest.map(PathBuf::from),
+                versioned,
+                delete,
+                dry_run,
+                trusted_peer,
+                trusted_key,
+                strict,
+                credentials,
+                seed,
+            };
+            if let Err(err) = download(md5, options).await {
                 eprintln!("download: {}", err);
                 panic!("download failed");
             }
@@ -0,0 +1,252 @@
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::server::LookupDirOrFile;
+
+const MANIFEST_FORMAT: &str = "p2psync-manifest-v1";
+
+/// The tree of a content id as published: every name, size and file hash
+/// below the id.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    pub format: String,
+    pub md5: String,
+    pub tree: LookupDirOrFile,
+}
+
+/// A manifest with the Ed25519 signature of its publisher. The signature
+/// covers the exact bytes of `manifest`, which is the manifest as JSON, so
+/// verifying does not depend on how the JSON is formatted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedManifest {
+    pub manifest: String,
+    pub public_key: String,
+    pub signature: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The Ed25519 key downloaders trust, as 64 hex digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey(Vec<u8>);
+
+impl FromStr for PublicKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match from_hex(s) {
+            Some(bytes) if bytes.len() == 32 => Ok(PublicKey(bytes)),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid public key {:?}, expected 64 hex digits", s),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&to_hex(&self.0))
+    }
+}
+
+/// The private key of a publisher, stored as a PKCS#8 file.
+pub struct SigningKey(Ed25519KeyPair);
+
+impl SigningKey {
+    /// Writes a new key to `path`, readable by the owner only, and returns
+    /// its public key.
+    pub fn generate(path: &Path) -> io::Result<PublicKey> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| io::Error::other("failed to generate a key"))?;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(pkcs8.as_ref())?;
+        Ok(Self::load(path)?.public_key())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let pkcs8 = std::fs::read(path)?;
+        Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map(SigningKey)
+            .map_err(|err| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.public_key().as_ref().to_vec())
+    }
+
+    pub fn sign(&self, md5: &str, tree: LookupDirOrFile) -> SignedManifest {
+        let manifest = serde_json::to_string(&Manifest {
+            format: MANIFEST_FORMAT.to_string(),
+            md5: md5.to_string(),
+            tree,
+        })
+        .unwrap();
+        SignedManifest {
+            public_key: self.public_key().to_string(),
+            signature: to_hex(self.0.sign(manifest.as_bytes()).as_ref()),
+            manifest,
+        }
+    }
+}
+
+impl SignedManifest {
+    /// The manifest, if it was signed by `key`.
+    pub fn verify(&self, key: &PublicKey) -> Result<Manifest, String> {
+        let signature =
+            from_hex(&self.signature).ok_or_else(|| "malformed signature".to_string())?;
+        signature::UnparsedPublicKey::new(&signature::ED25519, &key.0)
+            .verify(self.manifest.as_bytes(), &signature)
+            .map_err(|_| format!("not signed by {}", key))?;
+        let manifest = serde_json::from_str::<Manifest>(&self.manifest)
+            .map_err(|err| format!("malformed manifest: {}", err))?;
+        if manifest.format != MANIFEST_FORMAT {
+            return Err(format!("unsupported manifest format {:?}", manifest.format));
+        }
+        Ok(manifest)
+    }
+
+    /// The id the manifest is for, without checking the signature.
+    fn md5(&self) -> io::Result<String> {
+        serde_json::from_str::<Manifest>(&self.manifest)
+            .map(|manifest| manifest.md5)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+/// The manifests a server hands out: the ones it signs with its own key, and
+/// signed manifests loaded from files, for content it got from a publisher.
+#[derive(Default)]
+pub struct Manifests {
+    key: Option<SigningKey>,
+    loaded: HashMap<String, SignedManifest>,
+}
+
+impl Manifests {
+    pub fn load(key: Option<&Path>, files: &[PathBuf]) -> io::Result<Self> {
+        let mut loaded = HashMap::new();
+        for path in files.iter() {
+            let signed =
+                serde_json::from_slice::<SignedManifest>(&std::fs::read(path)?).map_err(|err| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{}: {}", path.display(), err),
+                    )
+                })?;
+            loaded.insert(signed.md5()?, signed);
+        }
+        Ok(Manifests {
+            key: key.map(SigningKey::load).transpose()?,
+            loaded,
+        })
+    }
+
+    /// The manifest of `md5`, whose tree on this server is `tree`.
+    pub fn get(&self, md5: &str, tree: Option<LookupDirOrFile>) -> Option<SignedManifest> {
+        match (self.loaded.get(md5), self.key.as_ref(), tree) {
+            (Some(signed), _, _) => Some(signed.clone()),
+            (None, Some(key), Some(tree)) => Some(key.sign(md5, tree)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> LookupDirOrFile {
+        LookupDirOrFile::Dir {
+            name: "root".to_string(),
+            children: vec![LookupDirOrFile::File {
+                name: "a".to_string(),
+                md5: "0cc175b9c0f1b6a831c399e269772661".to_string(),
+                size: 1,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("publisher.key");
+        let public_key = SigningKey::generate(&path)?;
+        assert_eq!(public_key.to_string().parse::<PublicKey>()?, public_key);
+        // an existing key is never overwritten
+        assert!(SigningKey::generate(&path).is_err());
+
+        let signed = SigningKey::load(&path)?.sign("id", tree());
+        let manifest = signed.verify(&public_key).unwrap();
+        assert_eq!(manifest.md5, "id");
+        assert_eq!(manifest.tree, tree());
+
+        // another publisher's key does not verify it
+        let other = SigningKey::generate(&dir.path().join("other.key"))?;
+        assert!(signed.verify(&other).is_err());
+
+        // neither does a tampered tree
+        let tampered = SignedManifest {
+            manifest: signed.manifest.replace("\"size\":1", "\"size\":2"),
+            ..signed.clone()
+        };
+        assert_ne!(tampered.manifest, signed.manifest);
+        assert!(tampered.verify(&public_key).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifests() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key_path = dir.path().join("publisher.key");
+        let public_key = SigningKey::generate(&key_path)?;
+        let manifest_path = dir.path().join("loaded.manifest");
+        let signed = SigningKey::load(&key_path)?.sign("loaded", tree());
+        std::fs::write(&manifest_path, serde_json::to_vec(&signed).unwrap())?;
+
+        // a server without the key only hands out the manifests it loaded
+        let manifests = Manifests::load(None, std::slice::from_ref(&manifest_path))?;
+        assert!(manifests.get("loaded", None).is_some());
+        assert!(manifests.get("other", Some(tree())).is_none());
+
+        let manifests = Manifests::load(Some(&key_path), &[manifest_path])?;
+        let signed = manifests.get("other", Some(tree())).unwrap();
+        assert_eq!(signed.verify(&public_key).unwrap().md5, "other");
+        assert!(manifests.get("missing", None).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_public_keys() {
+        for key in ["", "00", &"g".repeat(64), &"0".repeat(66)] {
+            assert!(key.parse::<PublicKey>().is_err(), "{:?}", key);
+        }
+    }
+}
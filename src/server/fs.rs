@@ -1,11 +1,14 @@
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+
+use crate::hash::HashAlgo;
+use crate::server::hash_cache::{FileStamp, HashCache};
 // use serde_binary::{Deserialize as DeserializeBinary, Serialize as SerializeBinary};
 use std::collections::HashMap;
-use std::fs;
+use std::ffi::OsStr;
 use std::io::{self, Read, Write};
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, SystemTime};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,6 +22,7 @@ struct FileOrDir {
     path: PathBuf,
     md5: String,
     special_fields: SpecialField,
+    stamp: Option<FileStamp>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -69,6 +73,46 @@ impl PartialEq for LookupDirOrFile {
 pub struct VirtualFileSystem {
     items: Vec<FileOrDir>,
     md5_to_id: HashMap<String, usize>,
+    // spec dumps record the algorithm next to the file system
+    #[serde(skip)]
+    algo: HashAlgo,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BaselineItem {
+    path: PathBuf,
+    md5: String,
+    special_fields: SpecialField,
+}
+
+/// `VirtualFileSystem` as spec dumps without a format header have it: md5
+/// items without stamps.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BaselineVirtualFileSystem {
+    items: Vec<BaselineItem>,
+    // rebuilt by the re-scan, only read since the format cannot skip fields
+    #[serde(rename = "md5_to_id")]
+    _md5_to_id: HashMap<String, usize>,
+}
+
+impl BaselineVirtualFileSystem {
+    /// The md5 of every file of the dump that still has its dumped size and
+    /// was last modified before `dumped_at`, the mtime of the dump in
+    /// nanoseconds, stamped as it is now. The dump has no stamps, so a file
+    /// rewritten with the same size since is only told apart by its mtime.
+    pub fn hash_cache(&self, dumped_at: i64) -> HashCache {
+        let mut cache = HashCache::new();
+        for item in self.items.iter() {
+            if let SpecialField::File { size } = item.special_fields
+                && let Ok(stamp) = FileStamp::from_path(item.path.as_path())
+                && stamp.size == size as u64
+                && stamp.mtime_ns < dumped_at
+            {
+                cache.insert(stamp, item.md5.clone());
+            }
+        }
+        cache
+    }
 }
 
 fn resolve_symlink(path: PathBuf) -> io::Result<PathBuf> {
@@ -81,18 +125,84 @@ fn resolve_symlink(path: PathBuf) -> io::Result<PathBuf> {
 
 const BUFFER_SIZE: usize = 4096;
 
+/// Prefix of every directory digest, bumped whenever the way a directory md5
+/// is computed changes.
+pub const DIR_DIGEST_VERSION: &str = "p2psync-dir-v2";
+
+/// The content id of a file under `algo`.
+pub fn hash_file(path: &Path, algo: HashAlgo) -> io::Result<String> {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut file = std::fs::File::open(path)?;
+    let begin = SystemTime::now();
+    let mut ctx = algo.hasher();
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        ctx.update(&buffer[..n]);
+    }
+    let end = SystemTime::now();
+    let duration = end.duration_since(begin).unwrap();
+    if duration > Duration::from_millis(100) {
+        println!(
+            "file {:?} read took {:.2} seconds",
+            path.file_name().unwrap(),
+            duration.as_millis() as f64 / 1000.0
+        );
+    }
+
+    Ok(ctx.finish())
+}
+
 impl VirtualFileSystem {
     pub fn new() -> Self {
         VirtualFileSystem {
             items: Vec::new(),
             md5_to_id: HashMap::new(),
+            algo: HashAlgo::default(),
         }
     }
 
+    pub fn algo(&self) -> HashAlgo {
+        self.algo
+    }
+
     pub fn lookup(&self, md5: &str) -> Option<LookupDirOrFile> {
         self.md5_to_id.get(md5).map(|id| self.id_to_lookup(*id))
     }
 
+    /// The ids of the roots the file system was built from.
+    pub fn root_md5s(&self) -> Vec<String> {
+        let mut is_child = vec![false; self.items.len()];
+        for item in self.items.iter() {
+            if let SpecialField::Dir { children } = &item.special_fields {
+                for child in children.iter() {
+                    is_child[*child] = true;
+                }
+            }
+        }
+        self.items
+            .iter()
+            .zip(is_child)
+            .filter(|(_, is_child)| !is_child)
+            .map(|(item, _)| item.md5.clone())
+            .collect()
+    }
+
+    /// The id of the file or directory at `path`, as it was added.
+    pub fn path_md5(&self, path: &Path) -> Option<String> {
+        self.items
+            .iter()
+            .find(|item| item.path == path)
+            .map(|item| item.md5.clone())
+    }
+
+    /// The ids of every file and directory.
+    pub fn md5s(&self) -> Vec<String> {
+        self.md5_to_id.keys().cloned().collect()
+    }
+
     pub fn file_path(&self, md5: &str) -> Result<PathBuf, io::Error> {
         match self.md5_to_id.get(md5) {
             Some(id) => match &self.items[*id].special_fields {
@@ -112,6 +222,39 @@ impl VirtualFileSystem {
             .into_owned()
     }
 
+    fn entry_name(&self, id: usize) -> &OsStr {
+        self.items[id].path.file_name().unwrap_or_default()
+    }
+
+    /// The content id of a directory whose `children` are sorted by name. Every
+    /// child contributes its type, name, size and md5, so renaming or moving
+    /// an entry changes the digest of each directory above it. The name of
+    /// the directory itself is not part of its digest, just like a file's.
+    fn dir_digest(&self, children: &[usize]) -> String {
+        let mut ctx = self.algo.hasher();
+        ctx.update(DIR_DIGEST_VERSION.as_bytes());
+        for child in children.iter() {
+            let name = self.entry_name(*child).as_encoded_bytes();
+            let (kind, size) = match &self.items[*child].special_fields {
+                SpecialField::Dir { children } => ("dir", children.len()),
+                SpecialField::File { size } => ("file", *size),
+            };
+            // the name is length prefixed, so no name can forge a next entry
+            ctx.update(
+                format!(
+                    "\n{} {} {} {}:",
+                    kind,
+                    size,
+                    self.items[*child].md5,
+                    name.len()
+                )
+                .as_bytes(),
+            );
+            ctx.update(name);
+        }
+        ctx.finish()
+    }
+
     fn id_to_lookup(&self, id: usize) -> LookupDirOrFile {
         match &self.items[id].special_fields {
             SpecialField::Dir { children } => {
@@ -128,51 +271,81 @@ impl VirtualFileSystem {
             SpecialField::File { size } => LookupDirOrFile::File {
                 name: self.file_name(id),
                 md5: self.items[id].md5.clone(),
-                size: size.clone(),
+                size: *size,
             },
         }
     }
 
-    pub fn seal(&mut self) -> io::Result<()> {
+    /// Like `file_path`, but also fails with `NotFound` when the file on disk
+    /// no longer has the size and mtime it had when it was hashed, so a stale
+    /// md5 is never served with new content.
+    pub fn unchanged_file_path(&self, md5: &str) -> Result<PathBuf, io::Error> {
+        let path = self.file_path(md5)?;
+        let id = self.md5_to_id[md5];
+        let current = FileStamp::from_path(path.as_path());
+        match (self.items[id].stamp, current) {
+            (Some(stamp), Ok(current)) if stamp == current => Ok(path),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "File changed")),
+        }
+    }
+
+    /// Builds and seals a new file system over `pathes`. Files whose stamp is
+    /// in `cache` (usually the `hash_cache` of a previous file system) keep
+    /// their md5 and are not read again. Returns the new file system together
+    /// with the number of files that had to be re-hashed.
+    pub fn rescan(
+        pathes: &[PathBuf],
+        cache: &HashCache,
+        algo: HashAlgo,
+    ) -> io::Result<(Self, usize)> {
+        let mut vfs = VirtualFileSystem {
+            algo,
+            ..VirtualFileSystem::new()
+        };
+        for p in pathes.iter() {
+            vfs.add(p.clone())?;
+        }
+        let rehashed = vfs.seal_with_cache(cache)?;
+        Ok((vfs, rehashed))
+    }
+
+    /// The stamp -> md5 map of every hashed file.
+    pub fn hash_cache(&self) -> HashCache {
+        let mut cache = HashCache::new();
+        for item in self.items.iter() {
+            if let Some(stamp) = item.stamp {
+                cache.insert(stamp, item.md5.clone());
+            }
+        }
+        cache
+    }
+
+    /// Seals the file system, taking the id of files whose stamp is in
+    /// `cache` with the file system's algorithm instead of reading them. Returns the number of re-hashed files.
+    pub fn seal_with_cache(&mut self, cache: &HashCache) -> io::Result<usize> {
         if !self.md5_to_id.is_empty() {
-            return Result::Err(io::Error::new(
-                io::ErrorKind::Other,
-                "VirtualFileSystem has been sealed",
-            ));
+            return Result::Err(io::Error::other("VirtualFileSystem has been sealed"));
         }
 
-        (&mut self.items)
-            .into_par_iter()
+        let rehashed = AtomicUsize::new(0);
+        self.items
+            .par_iter_mut()
             .try_for_each(|item| -> io::Result<()> {
                 match &item.special_fields {
                     SpecialField::Dir { .. } => Ok(()),
                     SpecialField::File { .. } => {
-                        let mut buffer = [0; BUFFER_SIZE];
-                        let mut file = std::fs::File::open(item.path.as_path())?;
-                        let file_size = fs::metadata(item.path.as_path())?.size();
-                        let begin = SystemTime::now();
-                        let mut ctx = md5::Context::new();
-                        loop {
-                            let n = file.read(&mut buffer)?;
-                            if n == 0 {
-                                break;
+                        let stamp = FileStamp::from_path(item.path.as_path())?;
+                        item.md5 = match cache.get_as(&stamp, self.algo) {
+                            Some(md5) => md5.to_string(),
+                            None => {
+                                rehashed.fetch_add(1, Ordering::Relaxed);
+                                hash_file(item.path.as_path(), self.algo)?
                             }
-                            ctx.consume(&buffer[..n]);
-                        }
-                        let end = SystemTime::now();
-                        let duration = end.duration_since(begin).unwrap();
-                        if duration > Duration::from_millis(100) {
-                            println!(
-                                "file {:?} read took {:.2} seconds",
-                                item.path.file_name().unwrap(),
-                                duration.as_millis() as f64 / 1000.0
-                            );
-                        }
-
-                        item.md5 = format!("{:x}", ctx.compute());
+                        };
                         item.special_fields = SpecialField::File {
-                            size: file_size as usize,
+                            size: stamp.size as usize,
                         };
+                        item.stamp = Some(stamp);
                         Ok(())
                     }
                 }
@@ -184,13 +357,12 @@ impl VirtualFileSystem {
 
                 if let SpecialField::Dir { children } = &item.special_fields {
                     let mut mut_children = children.clone();
-                    mut_children.sort_by(|a, b| self.items[*a].md5.cmp(&self.items[*b].md5));
-
-                    let mut md5_ctx = md5::Context::new();
-                    for child in mut_children.iter() {
-                        md5_ctx.consume(&self.items[*child].md5);
-                    }
-                    let md5 = format!("{:x}", md5_ctx.compute());
+                    mut_children.sort_by(|a, b| {
+                        self.entry_name(*a)
+                            .as_encoded_bytes()
+                            .cmp(self.entry_name(*b).as_encoded_bytes())
+                    });
+                    let md5 = self.dir_digest(&mut_children);
 
                     Some((mut_children, md5))
                 } else {
@@ -208,7 +380,7 @@ impl VirtualFileSystem {
             self.md5_to_id.insert(self.items[index].md5.clone(), index);
         }
 
-        Ok(())
+        Ok(rehashed.into_inner())
     }
 
     pub fn add(&mut self, path: PathBuf) -> io::Result<usize> {
@@ -229,9 +401,10 @@ impl VirtualFileSystem {
         }
 
         self.items.push(FileOrDir {
-            path: path,
+            path,
             md5: String::new(),
-            special_fields: SpecialField::Dir { children: children },
+            special_fields: SpecialField::Dir { children },
+            stamp: None,
         });
 
         Ok(self.items.len() - 1)
@@ -248,9 +421,10 @@ impl VirtualFileSystem {
         let id = self.items.len();
 
         self.items.push(FileOrDir {
-            path: path,
+            path,
             md5: String::new(),
             special_fields: SpecialField::File { size: 0 },
+            stamp: None,
         });
 
         Ok(id)
@@ -258,9 +432,9 @@ impl VirtualFileSystem {
 
     pub fn dump_md5<W: Write>(&self, mut w: W) -> io::Result<()> {
         for item in self.items.iter() {
-            write!(
+            writeln!(
                 w,
-                "{} {}: md5 {}\n",
+                "{} {}: md5 {}",
                 match item.special_fields {
                     SpecialField::Dir { .. } => "dir",
                     SpecialField::File { .. } => "file",
@@ -276,8 +450,11 @@ impl VirtualFileSystem {
 #[cfg(test)]
 mod tests {
     use super::VirtualFileSystem;
+    use crate::hash::HashAlgo;
+    use crate::server::HashCache;
     use std::io;
     use std::io::Write;
+    use std::path::Path;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -289,4 +466,92 @@ mod tests {
         assert!(file_id == 0);
         Ok(())
     }
+
+    #[test]
+    fn test_rescan_rehashes_only_changed_files() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a.txt"), b"a")?;
+        std::fs::write(dir.path().join("b.txt"), b"b")?;
+        let pathes = vec![dir.path().to_path_buf()];
+
+        let mut previous = VirtualFileSystem::new();
+        previous.add(dir.path().to_path_buf())?;
+        previous.seal_with_cache(&HashCache::new())?;
+        let root_md5 = previous.items.last().unwrap().md5.clone();
+
+        let (vfs, rehashed) =
+            VirtualFileSystem::rescan(&pathes, &previous.hash_cache(), HashAlgo::Md5)?;
+        assert_eq!(rehashed, 0);
+        assert!(vfs.lookup(&root_md5).is_some());
+
+        std::fs::write(dir.path().join("b.txt"), b"changed")?;
+        let (vfs, rehashed) = VirtualFileSystem::rescan(&pathes, &vfs.hash_cache(), HashAlgo::Md5)?;
+        assert_eq!(rehashed, 1);
+        assert!(vfs.lookup(&root_md5).is_none());
+        assert!(
+            vfs.lookup(&format!("{:x}", md5::compute(b"changed")))
+                .is_some()
+        );
+        assert!(vfs.lookup(&format!("{:x}", md5::compute(b"a"))).is_some());
+        assert_eq!(vfs.root_md5s(), vec![vfs.items.last().unwrap().md5.clone()]);
+        assert_eq!(vfs.md5s().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unchanged_file_path() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.txt");
+        std::fs::write(path.as_path(), b"a")?;
+        let mut vfs = VirtualFileSystem::new();
+        vfs.add(dir.path().to_path_buf())?;
+        vfs.seal_with_cache(&HashCache::new())?;
+
+        let md5 = format!("{:x}", md5::compute(b"a"));
+        assert_eq!(vfs.unchanged_file_path(&md5)?, path);
+        std::fs::write(path.as_path(), b"longer")?;
+        assert!(vfs.unchanged_file_path(&md5).is_err());
+        Ok(())
+    }
+
+    fn root_md5(root: &Path) -> io::Result<String> {
+        let (vfs, _) =
+            VirtualFileSystem::rescan(&[root.to_path_buf()], &HashCache::new(), HashAlgo::Md5)?;
+        Ok(vfs.items.last().unwrap().md5.clone())
+    }
+
+    #[test]
+    fn test_dir_md5_covers_names_and_structure() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let layout = |name: &str, files: &[(&str, &[u8])]| -> io::Result<String> {
+            let root = dir.path().join(name);
+            for (path, data) in files.iter() {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::fs::write(path, data)?;
+            }
+            root_md5(root.as_path())
+        };
+
+        let base = layout("base", &[("a.txt", b"a"), ("b.txt", b"b")])?;
+        // the root name is not part of the digest
+        assert_eq!(layout("same", &[("a.txt", b"a"), ("b.txt", b"b")])?, base);
+        assert_ne!(
+            layout("renamed", &[("a.txt", b"a"), ("c.txt", b"b")])?,
+            base
+        );
+        assert_ne!(
+            layout("swapped", &[("a.txt", b"b"), ("b.txt", b"a")])?,
+            base
+        );
+        assert_ne!(
+            layout("moved", &[("a.txt", b"a"), ("sub/b.txt", b"b")])?,
+            base
+        );
+        assert_ne!(
+            layout("nested", &[("sub/a.txt", b"a"), ("sub/b.txt", b"b")])?,
+            layout("flat", &[("sub/a.txt", b"a"), ("b.txt", b"b")])?
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,152 @@
+use crate::hash::HashAlgo;
+use serde::{Deserialize, Serialize};
+use serde_binary::binary_stream::Endian;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Identity of a file's content as far as the file system can tell: device,
+/// inode, size and modification time at the moment it was hashed. A file whose
+/// stamp is unchanged is assumed to still have the same md5.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileStamp {
+    pub dev: u64,
+    pub ino: u64,
+    pub size: u64,
+    pub mtime_ns: i64,
+}
+
+impl FileStamp {
+    pub fn of(meta: &fs::Metadata) -> Self {
+        FileStamp {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime_ns: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        Ok(Self::of(&fs::metadata(path)?))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HashCacheFile {
+    format: String,
+    entries: Vec<(FileStamp, String)>,
+}
+
+const HASH_CACHE_FORMAT: &str = "p2psync-hash-cache-v1";
+
+/// Persistent `FileStamp` -> md5 map, so that a cold start only hashes the
+/// files that changed since the cache was written.
+#[derive(Debug, Default)]
+pub struct HashCache {
+    entries: HashMap<FileStamp, String>,
+}
+
+impl HashCache {
+    pub fn new() -> Self {
+        HashCache {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Loads a cache written by `save`. A missing file gives an empty cache.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(err),
+        };
+        let file: HashCacheFile = serde_binary::from_slice(&data, Endian::Little)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if file.format != HASH_CACHE_FORMAT {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported hash cache format {:?}", file.format),
+            ));
+        }
+        Ok(HashCache {
+            entries: file.entries.into_iter().collect(),
+        })
+    }
+
+    /// Writes the cache to a temporary file next to `path` and renames it over
+    /// `path`, so a crash never leaves a truncated cache behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let file = HashCacheFile {
+            format: HASH_CACHE_FORMAT.to_string(),
+            entries: self
+                .entries
+                .iter()
+                .map(|(stamp, md5)| (*stamp, md5.clone()))
+                .collect(),
+        };
+        let data = serde_binary::to_vec(&file, Endian::Little).map_err(io::Error::other)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(tmp_path.as_os_str(), data)?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn get(&self, stamp: &FileStamp) -> Option<&str> {
+        self.entries.get(stamp).map(|md5| md5.as_str())
+    }
+
+    /// Like `get`, but only returns ids computed with `algo`, since a file
+    /// hashed with another algorithm has to be read again.
+    pub fn get_as(&self, stamp: &FileStamp, algo: HashAlgo) -> Option<&str> {
+        self.get(stamp)
+            .filter(|id| HashAlgo::of_id(id).is_ok_and(|of| of == algo))
+    }
+
+    pub fn insert(&mut self, stamp: FileStamp, md5: String) {
+        self.entries.insert(stamp, md5);
+    }
+
+    /// Adds every entry of `other`, overwriting entries with the same stamp.
+    pub fn merge(&mut self, other: HashCache) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileStamp, HashCache};
+    use std::io;
+
+    #[test]
+    fn test_save_and_load() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("a.txt");
+        std::fs::write(file_path.as_path(), b"a")?;
+        let stamp = FileStamp::from_path(file_path.as_path())?;
+
+        let cache_path = dir.path().join("hash.cache");
+        assert!(HashCache::load(cache_path.as_path())?.is_empty());
+
+        let mut cache = HashCache::new();
+        cache.insert(stamp, "md5_of_a".to_string());
+        cache.save(cache_path.as_path())?;
+
+        let cache = HashCache::load(cache_path.as_path())?;
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&stamp), Some("md5_of_a"));
+
+        std::fs::write(file_path.as_path(), b"changed")?;
+        assert_eq!(cache.get(&FileStamp::from_path(file_path.as_path())?), None);
+        Ok(())
+    }
+}
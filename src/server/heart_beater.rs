@@ -1,43 +1,103 @@
+use crate::auth::Credentials;
 use crate::tracker::AnnounceRequest;
-use reqwest;
 use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 
+/// How long a tracker may take to answer a deregistration on shutdown.
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct HeartBeater {
     handles: Vec<JoinHandle<()>>,
+    self_url: String,
+    trackers: Vec<String>,
+    client: Arc<reqwest::Client>,
 }
 
 impl HeartBeater {
-    pub fn new(self_url: String, trackers: Vec<String>, interval: Duration) -> Box<Self> {
-        let client = Arc::new(reqwest::Client::new());
+    /// Announces `self_url` to every tracker each `interval`, together with
+    /// the content ids in `md5s`. A change of `md5s` is announced right away.
+    /// Announces are sent with `credentials`.
+    pub fn new(
+        self_url: String,
+        trackers: Vec<String>,
+        interval: Duration,
+        md5s: watch::Receiver<Vec<String>>,
+        credentials: &Credentials,
+    ) -> Box<Self> {
+        let client = Arc::new(credentials.client_builder().build().unwrap());
 
         Box::new(HeartBeater {
             handles: trackers
                 .iter()
-                .map(|url| (url.clone(), client.clone(), self_url.clone()))
-                .map(move |(mut url, client, self_url)| {
+                .map(|url| (url.clone(), client.clone(), self_url.clone(), md5s.clone()))
+                .map(move |(mut url, client, self_url, mut md5s)| {
                     tokio::spawn(async move {
                         url.push_str("/announce");
                         loop {
                             let req = AnnounceRequest {
                                 addr: self_url.clone(),
+                                md5s: Some(md5s.borrow_and_update().clone()),
+                                relayed: false,
                             };
 
                             if let Err(err) = client.post(url.as_str()).json(&req).send().await {
                                 eprintln!("Failed to send heartbeat: {:?}", err);
                             }
 
-                            tokio::time::sleep(interval).await;
+                            tokio::select! {
+                                _ = tokio::time::sleep(interval) => {}
+                                Ok(()) = md5s.changed() => {}
+                            }
                         }
                     })
                 })
                 .collect::<Vec<_>>(),
+            self_url,
+            trackers,
+            client,
         })
     }
 
-    pub fn stop(&self) {
+    /// Stops the heartbeats and waits until they are gone, so no announce
+    /// is still in flight afterwards.
+    pub async fn stop(&mut self) {
         for handle in self.handles.iter() {
             handle.abort();
         }
+        for handle in self.handles.drain(..) {
+            if let Err(err) = handle.await
+                && !err.is_cancelled()
+            {
+                eprintln!("Heartbeat failed: {:?}", err);
+            }
+        }
+    }
+
+    /// Stops the heartbeats and removes `self_url` from every tracker, so
+    /// downloaders are not sent to this peer any more. An announce sent
+    /// before the heartbeats stopped cannot register it again afterwards.
+    pub async fn deregister(&mut self) {
+        self.stop().await;
+        let client = &self.client;
+        let req = AnnounceRequest {
+            addr: self.self_url.clone(),
+            md5s: None,
+            relayed: false,
+        };
+        let req = &req;
+        futures::future::join_all(self.trackers.iter().map(|url| async move {
+            let result = client
+                .delete(format!("{}/announce", url))
+                .timeout(DEREGISTER_TIMEOUT)
+                .json(req)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            if let Err(err) = result {
+                eprintln!("Failed to deregister from {}: {:?}", url, err);
+            }
+        }))
+        .await;
     }
 }
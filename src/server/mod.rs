@@ -1,6 +1,10 @@
 mod fs;
+mod hash_cache;
 mod heart_beater;
+mod range;
 mod svr;
+mod watcher;
 // Re-export LookupDirOrFile for external use
-pub use fs::LookupDirOrFile;
-pub use svr::{CreateArgs, startup};
+pub use fs::{LookupDirOrFile, hash_file};
+pub use hash_cache::{FileStamp, HashCache};
+pub use svr::{CreateArgs, SeedRequest, SeedResponse, ServeOptions, startup};
@@ -0,0 +1,178 @@
+/// A byte range of a file, `start..end` with `end` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    /// Value of the `Content-Range` header for this range of a `size` byte file.
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end - 1, size)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable `Range` header, the whole file is sent.
+    Full,
+    /// One or more satisfiable ranges, in the order they were asked for.
+    Partial(Vec<ByteRange>),
+    /// None of the ranges overlaps the file, answered with 416.
+    Unsatisfiable,
+}
+
+/// Requests asking for more ranges than this get the whole file instead.
+const MAX_RANGES: usize = 32;
+
+enum RangeSpec {
+    Satisfiable(ByteRange),
+    Unsatisfiable,
+}
+
+fn parse_range_spec(spec: &str, size: u64) -> Option<RangeSpec> {
+    let (first, last) = spec.trim().split_once('-')?;
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // first-last
+        (Ok(first), Ok(last)) if first <= last => ByteRange {
+            start: first,
+            end: last.saturating_add(1).min(size),
+        },
+        // first-
+        (Ok(first), Err(_)) if last.is_empty() => ByteRange {
+            start: first,
+            end: size,
+        },
+        // -suffix_length
+        (Err(_), Ok(suffix)) if first.is_empty() => ByteRange {
+            start: size.saturating_sub(suffix),
+            end: size,
+        },
+        _ => return None,
+    };
+
+    if range.start >= range.end {
+        Some(RangeSpec::Unsatisfiable)
+    } else {
+        Some(RangeSpec::Satisfiable(range))
+    }
+}
+
+/// Parses a `Range` header value such as `bytes=0-99,200-`, `bytes=100-` or
+/// `bytes=-100` against a file of `size` bytes. Headers that cannot be parsed
+/// are ignored as RFC 9110 allows, and ranges outside the file are dropped.
+pub fn parse_range(header: &str, size: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs.split(',').collect::<Vec<_>>(),
+        None => return RangeRequest::Full,
+    };
+    if specs.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        match parse_range_spec(spec, size) {
+            Some(RangeSpec::Satisfiable(range)) => ranges.push(range),
+            Some(RangeSpec::Unsatisfiable) => {}
+            None => return RangeRequest::Full,
+        }
+    }
+
+    if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+/// The strong entity tag of a file, its quoted content md5.
+pub fn etag(md5: &str) -> String {
+    format!("\"{}\"", md5)
+}
+
+/// Whether an `If-None-Match` header value matches `etag`. Weak tags compare
+/// equal to strong ones here, as RFC 9110 requires for this header.
+pub fn if_none_match(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Whether the `Range` header may be honoured under an `If-Range` header
+/// value. Only a strong match of `etag` counts, dates are never trusted
+/// since content is identified by md5 alone.
+pub fn if_range(header: &str, etag: &str) -> bool {
+    header.trim() == etag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteRange, RangeRequest, etag, if_none_match, if_range, parse_range};
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            ranges
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), partial(&[(0, 100)]));
+        assert_eq!(parse_range("bytes=900-2000", 1000), partial(&[(900, 1000)]));
+        assert_eq!(parse_range("bytes=100-", 1000), partial(&[(100, 1000)]));
+        assert_eq!(parse_range("bytes=-100", 1000), partial(&[(900, 1000)]));
+        assert_eq!(parse_range("bytes=-2000", 1000), partial(&[(0, 1000)]));
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_parse_multi_range() {
+        assert_eq!(
+            parse_range("bytes=0-1, 5-6,-2", 1000),
+            partial(&[(0, 2), (5, 7), (998, 1000)])
+        );
+        // unsatisfiable parts are dropped
+        assert_eq!(parse_range("bytes=0-1,2000-3000", 1000), partial(&[(0, 2)]));
+        assert_eq!(
+            parse_range("bytes=1000-,2000-3000", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=0-1,x", 1000), RangeRequest::Full);
+        let many = format!("bytes={}", vec!["0-1"; 33].join(","));
+        assert_eq!(parse_range(&many, 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_content_range() {
+        let range = ByteRange { start: 0, end: 100 };
+        assert_eq!(range.content_range(1000), "bytes 0-99/1000");
+    }
+
+    #[test]
+    fn test_preconditions() {
+        let tag = etag("abc");
+        assert_eq!(tag, "\"abc\"");
+        assert!(if_none_match("\"abc\"", &tag));
+        assert!(if_none_match("\"x\", W/\"abc\"", &tag));
+        assert!(if_none_match("*", &tag));
+        assert!(!if_none_match("\"x\"", &tag));
+        assert!(if_range("\"abc\"", &tag));
+        assert!(!if_range("W/\"abc\"", &tag));
+        assert!(!if_range("Wed, 21 Oct 2015 07:28:00 GMT", &tag));
+    }
+}
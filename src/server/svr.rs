@@ -1,79 +1,353 @@
+use axum::body::Bytes;
 use axum::{
     Json, Router,
     body::Body,
-    extract::{Query, State},
-    http::StatusCode,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Response},
 };
+use futures::{
+    StreamExt, TryStreamExt, future,
+    stream::{self, BoxStream},
+};
 use serde::{Deserialize, Serialize};
 use serde_binary::binary_stream::Endian;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{ErrorKind, Write, stderr},
-    path::PathBuf,
-    sync::Arc,
-    time::Duration,
+    io::{ErrorKind, SeekFrom, Write, stderr},
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
+use crate::auth::{self, Credentials};
+use crate::dht::Dht;
+use crate::hash::HashAlgo;
+use crate::lan::{self, ANNOUNCE_INTERVAL, LAN_GROUP};
+use crate::manifest::Manifests;
 use crate::server::fs;
+use crate::server::hash_cache::{FileStamp, HashCache};
 use crate::server::heart_beater::HeartBeater;
-use axum::routing::get;
-use tokio::{net::TcpListener, sync::RwLock};
+use crate::server::range::{self, ByteRange, RangeRequest};
+use crate::server::watcher::DirWatcher;
+use crate::tls::{self, PeerAddr, ServerListener, ServerTls};
+use crate::tracker;
+use axum::routing::{get, post};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, Take},
+    sync::{RwLock, watch},
+    task::JoinHandle,
+};
 use tokio_util::io::ReaderStream;
 
+/// How long a replaced file system keeps answering for its md5s after the
+/// last download that used it has finished.
+const RETIRED_GRACE: Duration = Duration::from_secs(300);
+
+/// Quiet period after the last file system event before re-hashing.
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// How often `--tag` is published again while no tracker took it.
+const TAG_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+struct RetiredVfs {
+    vfs: Arc<fs::VirtualFileSystem>,
+    last_used: Instant,
+}
+
 struct AppState {
-    vfs: RwLock<Box<fs::VirtualFileSystem>>,
+    vfs: RwLock<Arc<fs::VirtualFileSystem>>,
+    // file systems replaced by `reload`, newest last
+    retired: Mutex<Vec<RetiredVfs>>,
     pathes: Vec<PathBuf>,
+    // roots added through `/seed`, dropped again once they are removed
+    seeded: Mutex<Vec<PathBuf>>,
+    hash_cache: Option<PathBuf>,
 }
 
+/// Written as the first field of a spec dump, bumped whenever the layout of
+/// `VirtualFileSystem` changes.
+const DUMP_FORMAT: &str = "p2psync-spec-v4";
+
+/// Spec dumps written before the hash algorithm was recorded, which are all
+/// md5. Their file md5s are still valid, the directory md5s are recomputed by
+/// the re-scan on load since v2 and dumps without a format header predate the
+/// current directory digest.
+const LEGACY_DUMP_FORMATS: &[&str] = &["p2psync-spec-v2", "p2psync-spec-v3"];
+
 #[derive(Debug, Serialize)]
 struct AppStateDumpItem<'a> {
+    format: &'a str,
     vfs: &'a fs::VirtualFileSystem,
     pathes: &'a Vec<PathBuf>,
+    hash: HashAlgo,
 }
 
 #[derive(Debug, Deserialize, Default)]
 struct AppStateLoadItem {
+    format: String,
     vfs: Box<fs::VirtualFileSystem>,
     pathes: Vec<PathBuf>,
+    // missing from legacy dumps, which are all md5
+    #[serde(default)]
+    hash: HashAlgo,
+}
+
+/// Spec dumps written before the format header, reported as this format.
+const BASELINE_DUMP_FORMAT: &str = "p2psync-spec-v1";
+
+/// How dumps without a format header start: two fields, `vfs` first. The
+/// binary format panics on fields it does not know, so only dumps that start
+/// like this are read with their layout.
+const BASELINE_DUMP_START: &[u8] = b"\x02\0\0\0\x03\0\0\0vfs";
+
+/// The layout of spec dumps without a format header. They are all md5 and
+/// have no stamps, their directory md5s predate the current digest.
+#[derive(Debug, Serialize, Deserialize)]
+struct BaselineLoadItem {
+    vfs: fs::BaselineVirtualFileSystem,
+    pathes: Vec<PathBuf>,
+}
+
+fn load_hash_cache(hash_cache: Option<&PathBuf>) -> std::io::Result<HashCache> {
+    match hash_cache {
+        Some(path) => HashCache::load(path),
+        None => Ok(HashCache::new()),
+    }
+}
+
+fn save_hash_cache(
+    hash_cache: Option<&PathBuf>,
+    vfs: &fs::VirtualFileSystem,
+) -> std::io::Result<()> {
+    match hash_cache {
+        // only live files are written, so the cache never grows stale
+        Some(path) => vfs.hash_cache().save(path),
+        None => Ok(()),
+    }
 }
 
 impl AppState {
-    pub fn new(pathes: Vec<String>) -> std::io::Result<Self> {
-        let mut vfs = Box::new(fs::VirtualFileSystem::new());
+    pub fn new(
+        pathes: Vec<String>,
+        hash_cache: Option<PathBuf>,
+        algo: HashAlgo,
+    ) -> std::io::Result<Self> {
+        Self::with_known(pathes, hash_cache, algo, HashCache::new())
+    }
+
+    /// Like `new`, taking the ids of the files in `known` instead of hashing
+    /// them, for trees that were verified when they were downloaded.
+    fn with_known(
+        pathes: Vec<String>,
+        hash_cache: Option<PathBuf>,
+        algo: HashAlgo,
+        known: HashCache,
+    ) -> std::io::Result<Self> {
         let path_buffers = pathes.into_iter().map(PathBuf::from).collect::<Vec<_>>();
-        for p in path_buffers.iter() {
-            vfs.add(p.clone())?;
-        }
-        vfs.seal()?;
+        let mut cache = load_hash_cache(hash_cache.as_ref())?;
+        cache.merge(known);
+        let (vfs, rehashed) = fs::VirtualFileSystem::rescan(&path_buffers, &cache, algo)?;
         vfs.dump_md5(stderr())?;
+        if hash_cache.is_some() {
+            eprintln!(
+                "{} files hashed, the rest taken from the hash cache",
+                rehashed
+            );
+        }
+
+        save_hash_cache(hash_cache.as_ref(), &vfs)?;
+
         Ok(Self {
-            vfs: RwLock::new(vfs),
+            vfs: RwLock::new(Arc::new(vfs)),
+            retired: Mutex::new(Vec::new()),
             pathes: path_buffers,
+            seeded: Mutex::new(Vec::new()),
+            hash_cache,
         })
     }
 
-    pub fn load_from_binary(file: String) -> std::io::Result<Self> {
+    /// Loads a spec dump. Files are re-scanned and every file whose stamp no
+    /// longer matches the dump is re-hashed, so a stale dump is never served.
+    /// Files are hashed with `algo` if given, otherwise with the algorithm of
+    /// the dump. Dumps without a format header are loaded as md5 dumps.
+    pub fn load_from_binary(
+        file: String,
+        hash_cache: Option<PathBuf>,
+        algo: Option<HashAlgo>,
+    ) -> std::io::Result<Self> {
+        let dumped_at = FileStamp::from_path(Path::new(&file))?.mtime_ns;
         let data = std::fs::read(file)?;
-        let load_item: AppStateLoadItem = serde_binary::from_slice(&data, Endian::Little)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let load_item = serde_binary::from_slice(&data, Endian::Little).ok().filter(
+            |item: &AppStateLoadItem| {
+                item.format == DUMP_FORMAT || LEGACY_DUMP_FORMATS.contains(&item.format.as_str())
+            },
+        );
+        let (format, pathes, dumped, hash) = match load_item {
+            Some(item) => (item.format, item.pathes, item.vfs.hash_cache(), item.hash),
+            None => {
+                let item: BaselineLoadItem = Some(&data)
+                    .filter(|data| data.starts_with(BASELINE_DUMP_START))
+                    .and_then(|data| serde_binary::from_slice(data, Endian::Little).ok())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(
+                                "not a {} spec dump, re-create it with --path and --dump-path",
+                                DUMP_FORMAT
+                            ),
+                        )
+                    })?;
+                (
+                    BASELINE_DUMP_FORMAT.to_string(),
+                    item.pathes,
+                    item.vfs.hash_cache(dumped_at),
+                    HashAlgo::Md5,
+                )
+            }
+        };
+
+        let mut cache = load_hash_cache(hash_cache.as_ref())?;
+        cache.merge(dumped);
+        let (vfs, rehashed) = fs::VirtualFileSystem::rescan(&pathes, &cache, algo.unwrap_or(hash))?;
+        let migrated = format != DUMP_FORMAT;
+        if migrated {
+            eprintln!(
+                "{} spec dump, directory md5s were recomputed, dump it again with --dump-path",
+                format
+            );
+        }
+        if rehashed > 0 {
+            eprintln!(
+                "{} files changed since the spec was dumped, re-hashed",
+                rehashed
+            );
+        }
+        if rehashed > 0 || migrated {
+            vfs.dump_md5(stderr())?;
+        }
+
+        save_hash_cache(hash_cache.as_ref(), &vfs)?;
 
         Ok(Self {
-            vfs: RwLock::new(load_item.vfs),
-            pathes: load_item.pathes,
+            vfs: RwLock::new(Arc::new(vfs)),
+            retired: Mutex::new(Vec::new()),
+            pathes,
+            seeded: Mutex::new(Vec::new()),
+            hash_cache,
         })
     }
 
+    /// The served roots: the ones it was started with, then the seeded ones
+    /// that still exist.
+    fn roots(&self) -> Vec<PathBuf> {
+        let mut seeded = self.seeded.lock().unwrap();
+        seeded.retain(|path| {
+            let exists = path.exists();
+            if !exists {
+                eprintln!("{} was removed, no longer seeding it", path.display());
+            }
+            exists
+        });
+        self.pathes.iter().chain(seeded.iter()).cloned().collect()
+    }
+
+    /// Serves `path` from the next `reload` on, unless it is served already.
+    fn add_seeded(&self, path: PathBuf) {
+        let mut seeded = self.seeded.lock().unwrap();
+        if !self.pathes.contains(&path) && !seeded.contains(&path) {
+            seeded.push(path);
+        }
+    }
+
+    /// Re-scans every root, re-hashing only changed files that are not in
+    /// `known`, and swaps the new file system in. The old one is kept so
+    /// that its md5s keep resolving while downloads are still using them.
+    pub async fn reload(&self, known: HashCache) -> std::io::Result<()> {
+        let previous = self.vfs.read().await.clone();
+        let pathes = self.roots();
+        let (vfs, rehashed) = tokio::task::spawn_blocking(move || {
+            let mut cache = previous.hash_cache();
+            cache.merge(known);
+            fs::VirtualFileSystem::rescan(&pathes, &cache, previous.algo())
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        vfs.dump_md5(stderr())?;
+        eprintln!("reloaded, {} files re-hashed", rehashed);
+        save_hash_cache(self.hash_cache.as_ref(), &vfs)?;
+
+        let previous = std::mem::replace(&mut *self.vfs.write().await, Arc::new(vfs));
+        let mut retired = self.retired.lock().unwrap();
+        retired.push(RetiredVfs {
+            vfs: previous,
+            last_used: Instant::now(),
+        });
+        Self::prune_retired(&mut retired);
+        Ok(())
+    }
+
+    fn prune_retired(retired: &mut Vec<RetiredVfs>) {
+        retired.retain_mut(|r| {
+            // a strong count above one means a download still streams from it
+            if Arc::strong_count(&r.vfs) > 1 {
+                r.last_used = Instant::now();
+            }
+            r.last_used.elapsed() < RETIRED_GRACE
+        });
+    }
+
+    /// The content ids announced to the trackers, the roots or all of them.
+    async fn announced_md5s(&self, all: bool) -> Vec<String> {
+        let vfs = self.vfs.read().await;
+        if all { vfs.md5s() } else { vfs.root_md5s() }
+    }
+
+    /// The current file system followed by the retired ones, newest first.
+    async fn generations(&self) -> Vec<Arc<fs::VirtualFileSystem>> {
+        let mut generations = vec![self.vfs.read().await.clone()];
+        let mut retired = self.retired.lock().unwrap();
+        Self::prune_retired(&mut retired);
+        generations.extend(retired.iter().rev().map(|r| r.vfs.clone()));
+        generations
+    }
+
+    /// Resolves a file md5 to a path, falling back to retired file systems
+    /// whose file has not changed on disk since. The returned file system must
+    /// be held for as long as the file is being read.
+    async fn resolve_file(
+        &self,
+        md5: &str,
+    ) -> std::io::Result<(PathBuf, Arc<fs::VirtualFileSystem>)> {
+        let mut generations = self.generations().await.into_iter();
+        let current = generations.next().unwrap();
+        let err = match current.file_path(md5) {
+            Ok(path) => return Ok((path, current)),
+            Err(err) => err,
+        };
+        for vfs in generations {
+            if let Ok(path) = vfs.unchanged_file_path(md5) {
+                return Ok((path, vfs));
+            }
+        }
+        Err(err)
+    }
+
     pub async fn dump<W: Write>(&self, mut w: W) -> std::io::Result<()> {
         let read_guard = self.vfs.read().await;
+        // seeded roots are served like the others once the dump is loaded
+        let pathes = self.roots();
         let dump_item = AppStateDumpItem {
+            format: DUMP_FORMAT,
             vfs: read_guard.as_ref(),
-            pathes: &self.pathes,
+            pathes: &pathes,
+            hash: read_guard.algo(),
         };
 
-        let binary_data = serde_binary::to_vec(&dump_item, Endian::Little)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let binary_data =
+            serde_binary::to_vec(&dump_item, Endian::Little).map_err(std::io::Error::other)?;
         w.write_all(&binary_data)?;
         Ok(())
     }
@@ -88,7 +362,12 @@ async fn query(
         None => return StatusCode::BAD_REQUEST.into_response(),
     };
 
-    let resp = match state.vfs.read().await.lookup(md5) {
+    let resp = match state
+        .generations()
+        .await
+        .iter()
+        .find_map(|vfs| vfs.lookup(md5))
+    {
         Some(_resp) => _resp,
         None => return StatusCode::NOT_FOUND.into_response(),
     };
@@ -96,83 +375,1122 @@ async fn query(
     Json(resp).into_response()
 }
 
+/// The signed manifest of an id, next to its content.
+async fn manifest(
+    State((state, manifests)): State<(Arc<AppState>, Arc<Manifests>)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let md5 = match params.get("md5") {
+        Some(_md5) => _md5,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let tree = state
+        .generations()
+        .await
+        .iter()
+        .find_map(|vfs| vfs.lookup(md5));
+    match manifests.get(md5, tree) {
+        Some(signed) => Json(signed).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Asks a server to serve a tree downloaded on its host as another root.
+/// The ids its files were verified against are passed along with the stamps
+/// the files had then, so files that are unchanged are not hashed again.
+/// Stamps of anything but the regular files under `path` are ignored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeedRequest {
+    pub path: PathBuf,
+    pub files: Vec<(FileStamp, String)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SeedResponse {
+    /// The id `path` is served as.
+    pub md5: String,
+}
+
+/// Re-scans the served roots and hands the new ids to the announcers and
+/// the spec dump.
+struct Reloader {
+    state: Arc<AppState>,
+    md5s: watch::Sender<Vec<String>>,
+    // the root ids alone, for the lan where every id of --announce-all
+    // would flood the segment
+    roots: watch::Sender<Vec<String>>,
+    announce_all: bool,
+    dump_path: Option<String>,
+}
+
+impl Reloader {
+    async fn reload(&self, known: HashCache) -> std::io::Result<()> {
+        self.state.reload(known).await?;
+        self.md5s
+            .send_replace(self.state.announced_md5s(self.announce_all).await);
+        self.roots
+            .send_replace(self.state.announced_md5s(false).await);
+        if let Some(dump_path) = self.dump_path.as_ref()
+            && let Err(err) = async { self.state.dump(File::create(dump_path)?).await }.await
+        {
+            eprintln!("Failed to dump: {:?}", err);
+        }
+        Ok(())
+    }
+}
+
+/// Adds the roots posted to `/seed`.
+struct Seeding {
+    reloader: Arc<Reloader>,
+    watcher: Option<Arc<DirWatcher>>,
+    // the address the server is bound to
+    bound: IpAddr,
+    // seeded trees must be inside this directory, canonical
+    dir: PathBuf,
+}
+
+impl Seeding {
+    /// Whether a client at `ip` runs on this host. A local client of a
+    /// server bound to one address connects from that address.
+    fn is_local(&self, ip: IpAddr) -> bool {
+        ip.is_loopback() || (!self.bound.is_unspecified() && ip == self.bound)
+    }
+
+    /// Serves `request.path` and returns its id.
+    async fn seed(&self, request: SeedRequest) -> std::io::Result<String> {
+        let path = std::fs::canonicalize(&request.path)?;
+        if !path.starts_with(&self.dir) {
+            return Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{} is not inside the seed directory {}",
+                    path.display(),
+                    self.dir.display()
+                ),
+            ));
+        }
+        let known = known_under(&path, request.files)?;
+        let state = &self.reloader.state;
+        state.add_seeded(path.clone());
+        if let Some(watcher) = self.watcher.as_ref() {
+            // also when it is served already, a download swaps the tree out
+            watcher.watch(&path).map_err(std::io::Error::other)?;
+        }
+        self.reloader.reload(known).await?;
+        state.vfs.read().await.path_md5(&path).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} is not served", path.display()),
+            )
+        })
+    }
+}
+
+/// The entries of `files` that are the stamps of regular files under `path`
+/// as they are now. Any other stamp could give a file of another served root,
+/// or one reached through a symlink, an id it was never verified against.
+fn known_under(path: &Path, files: Vec<(FileStamp, String)>) -> std::io::Result<HashCache> {
+    let mut stamps = HashSet::new();
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let meta = std::fs::symlink_metadata(&dir)?;
+        if meta.is_file() {
+            stamps.insert(FileStamp::of(&meta));
+            continue;
+        }
+        if !meta.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&dir)? {
+            dirs.push(entry?.path());
+        }
+    }
+    let mut known = HashCache::new();
+    for (stamp, md5) in files {
+        if stamps.contains(&stamp) {
+            known.insert(stamp, md5);
+        }
+    }
+    Ok(known)
+}
+
+/// Serves a tree downloaded on this host, see `SeedRequest`.
+async fn seed(
+    State(seeding): State<Arc<Seeding>>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    Json(request): Json<SeedRequest>,
+) -> Response {
+    // any path could be served this way, only the host itself may add one
+    if !seeding.is_local(peer.ip()) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    match seeding.seed(request).await {
+        Ok(md5) => Json(SeedResponse { md5 }).into_response(),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        }
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            (StatusCode::FORBIDDEN, err.to_string()).into_response()
+        }
+        Err(err) => internal_error(err),
+    }
+}
+
+fn internal_error(err: std::io::Error) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Internal server error: {}", err),
+    )
+        .into_response()
+}
+
+/// Streams `range` of the file at `path`.
+async fn file_segment(
+    path: PathBuf,
+    range: ByteRange,
+) -> std::io::Result<ReaderStream<Take<tokio::fs::File>>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    Ok(ReaderStream::with_capacity(
+        file.take(range.len()),
+        4 * 1024 * 1024,
+    ))
+}
+
+const MULTIPART_BOUNDARY: &str = "p2psync-byteranges";
+
+/// The `multipart/byteranges` body for several ranges of one file, together
+/// with its length.
+fn multipart_body(
+    path: PathBuf,
+    ranges: Vec<ByteRange>,
+    size: u64,
+) -> (BoxStream<'static, std::io::Result<Bytes>>, u64) {
+    let part_header = move |range: &ByteRange| {
+        Bytes::from(format!(
+            "\r\n--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: {}\r\n\r\n",
+            MULTIPART_BOUNDARY,
+            range.content_range(size)
+        ))
+    };
+    let trailer = Bytes::from(format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY));
+    let length = ranges
+        .iter()
+        .map(|range| part_header(range).len() as u64 + range.len())
+        .sum::<u64>()
+        + trailer.len() as u64;
+
+    let parts = ranges.into_iter().map(move |range| {
+        stream::once(future::ready(Ok(part_header(&range))))
+            .chain(stream::once(file_segment(path.clone(), range)).try_flatten())
+    });
+    let body = stream::iter(parts)
+        .flatten()
+        .chain(stream::once(future::ready(Ok(trailer))))
+        .boxed();
+    (body, length)
+}
+
 async fn download(
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     let md5 = match params.get("md5") {
         Some(_md5) => _md5,
         None => return StatusCode::BAD_REQUEST.into_response(),
     };
 
-    let path = match state.vfs.read().await.file_path(md5) {
-        Ok(_path) => _path,
+    let (path, vfs) = match state.resolve_file(md5).await {
+        Ok(_resolved) => _resolved,
         Err(err) => {
             if err.kind() == ErrorKind::NotFound {
                 return (StatusCode::NOT_FOUND, format!("File not found {}", md5)).into_response();
             } else {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Internal server error: {}", err),
-                )
-                    .into_response();
+                return internal_error(err);
             }
         }
     };
-    let file = match tokio::fs::File::open(path).await {
-        Ok(file) => file,
+    let size = match tokio::fs::metadata(path.as_path()).await {
+        Ok(meta) => meta.len(),
         Err(err) => {
             return (StatusCode::NOT_FOUND, format!("File not found: {}", err)).into_response();
         }
     };
 
-    let stream = ReaderStream::with_capacity(file, 4 * 1024 * 1024);
-    let body = Body::from_stream(stream);
-    body.into_response()
+    let etag = range::etag(md5);
+    let header_str = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if header_str(header::IF_NONE_MATCH).is_some_and(|value| range::if_none_match(value, &etag)) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    let range = match header_str(header::RANGE) {
+        Some(value) if header_str(header::IF_RANGE).is_none_or(|v| range::if_range(v, &etag)) => {
+            range::parse_range(value, size)
+        }
+        _ => RangeRequest::Full,
+    };
+    let response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, etag);
+    let (response, body, length) = match range {
+        RangeRequest::Full => {
+            let full = ByteRange {
+                start: 0,
+                end: size,
+            };
+            match file_segment(path, full).await {
+                Ok(segment) => (response.status(StatusCode::OK), segment.boxed(), size),
+                Err(err) => return internal_error(err),
+            }
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            match file_segment(path, ranges[0]).await {
+                Ok(segment) => (
+                    response
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(header::CONTENT_RANGE, ranges[0].content_range(size)),
+                    segment.boxed(),
+                    ranges[0].len(),
+                ),
+                Err(err) => return internal_error(err),
+            }
+        }
+        RangeRequest::Partial(ranges) => {
+            let (body, length) = multipart_body(path, ranges, size);
+            (
+                response.status(StatusCode::PARTIAL_CONTENT).header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={}", MULTIPART_BOUNDARY),
+                ),
+                body,
+                length,
+            )
+        }
+        RangeRequest::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            )
+                .into_response();
+        }
+    };
+
+    // the stream keeps `vfs` alive, so a retired file system stays available
+    // until every download from it has finished
+    let body = body.map(move |chunk| {
+        let _ = &vfs;
+        chunk
+    });
+    response
+        .header(header::CONTENT_LENGTH, length)
+        .body(Body::from_stream(body))
+        .unwrap()
+        .into_response()
+}
+
+/// Answers the health probes of trackers.
+async fn health() -> &'static str {
+    "ok"
 }
 
-fn build_app(app_state: Arc<AppState>) -> Router {
-    let router = Router::new()
+fn build_app(
+    app_state: Arc<AppState>,
+    manifests: Arc<Manifests>,
+    seeding: Option<Arc<Seeding>>,
+    token: Option<String>,
+) -> Router {
+    let mut router = Router::new()
         .route("/query", get(query))
         .route("/download", get(download))
-        .with_state(app_state);
-
-    router
+        .with_state(app_state.clone())
+        .merge(
+            Router::new()
+                .route("/manifest", get(manifest))
+                .with_state((app_state, manifests)),
+        );
+    if let Some(seeding) = seeding {
+        router = router.merge(Router::new().route("/seed", post(seed)).with_state(seeding));
+    }
+    // trackers probe the health of peers without the token
+    auth::protect(router, token).route("/health", get(health))
 }
 
 pub enum CreateArgs {
     Pathes(Vec<String>),
     LoadPath(String),
+    /// A downloaded tree, with the stamps and ids of its verified files.
+    Seed {
+        path: PathBuf,
+        known: HashCache,
+    },
 }
 
-pub async fn startup(
-    args: CreateArgs,
-    address: String,
-    port: u16,
-    dump_path: Option<String>,
-    tracker: Vec<String>,
-) -> std::io::Result<()> {
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    pub address: String,
+    pub port: u16,
+    pub dump_path: Option<String>,
+    pub tracker: Vec<String>,
+    /// Re-hashes the served directories when they change.
+    pub watch: bool,
+    /// Caches the md5 of served files across restarts.
+    pub hash_cache: Option<PathBuf>,
+    /// Algorithm of the content ids, md5 if unset. A loaded spec dump keeps
+    /// its own algorithm unless this is set.
+    pub hash: Option<HashAlgo>,
+    /// Announces the id of every file and directory to the trackers instead
+    /// of only the ids of the served roots.
+    pub announce_all: bool,
+    /// Joins the DHT through these nodes and publishes the announced ids
+    /// there. `Some` with no nodes starts a new DHT.
+    pub dht: Option<Vec<String>>,
+    /// Multicasts the announced ids on the local network.
+    pub lan: bool,
+    /// How long transfers in flight may take to finish on shutdown.
+    pub drain_timeout: Duration,
+    /// The token of `credentials` is required by `/query` and `/download`,
+    /// and all of them are sent to the trackers and DHT nodes.
+    pub credentials: Credentials,
+    /// Serves over TLS, announced as an `https://` URL.
+    pub tls: Option<ServerTls>,
+    /// Signs the manifests of the served ids with the publisher key in this
+    /// PKCS#8 file.
+    pub sign_key: Option<PathBuf>,
+    /// Signed manifests to serve as they are, for content signed elsewhere.
+    pub manifests: Vec<PathBuf>,
+    /// Points this tag at the served root on the trackers at startup, and
+    /// again on SIGHUP. Needs exactly one served root.
+    pub tag: Option<String>,
+    /// Accepts `/seed` from this host for trees inside this directory. Needs
+    /// the token of `credentials`, `/seed` is not served without it.
+    pub seed_dir: Option<PathBuf>,
+}
+
+/// Points tag `name` at the served root on `trackers` at startup, and again
+/// on SIGHUP, retrying every `TAG_RETRY_INTERVAL` until a tracker takes it.
+/// Reloads do not move the tag, so a half-written tree is never published.
+fn spawn_tag_publisher(
+    name: String,
+    state: Arc<AppState>,
+    trackers: Vec<String>,
+    credentials: &Credentials,
+) -> std::io::Result<JoinHandle<()>> {
+    let client = credentials.client_builder().build().unwrap();
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    Ok(tokio::spawn(async move {
+        loop {
+            if let Some(root) = state.announced_md5s(false).await.pop() {
+                match tracker::publish_tag(&client, &trackers, &name, &root).await {
+                    Ok(_) => eprintln!("tag {} points to {}", name, root),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        tokio::time::sleep(TAG_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+            if hangup.recv().await.is_none() {
+                break;
+            }
+        }
+    }))
+}
+
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install the SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+pub async fn startup(args: CreateArgs, options: ServeOptions) -> std::io::Result<()> {
+    let ServeOptions {
+        address,
+        port,
+        dump_path,
+        tracker,
+        watch,
+        hash_cache,
+        hash,
+        announce_all,
+        dht,
+        lan,
+        drain_timeout,
+        credentials,
+        tls,
+        sign_key,
+        manifests,
+        tag,
+        seed_dir,
+    } = options;
+    let manifests = Arc::new(Manifests::load(sign_key.as_deref(), &manifests)?);
     let app_state = Arc::new(match args {
-        CreateArgs::Pathes(pathes) => AppState::new(pathes)?,
-        CreateArgs::LoadPath(path) => AppState::load_from_binary(path)?,
+        CreateArgs::Pathes(pathes) => AppState::new(pathes, hash_cache, hash.unwrap_or_default())?,
+        CreateArgs::LoadPath(path) => AppState::load_from_binary(path, hash_cache, hash)?,
+        CreateArgs::Seed { path, known } => AppState::with_known(
+            vec![path.to_string_lossy().into_owned()],
+            hash_cache,
+            hash.unwrap_or_default(),
+            known,
+        )?,
     });
-    if let Some(dump_path) = dump_path {
+    if let Some(dump_path) = dump_path.as_ref() {
         app_state.dump(File::create(dump_path)?).await?;
     }
+    let (md5s_tx, md5s_rx) = watch::channel(app_state.announced_md5s(announce_all).await);
+    let (roots_tx, roots_rx) = watch::channel(app_state.announced_md5s(false).await);
+    let reloader = Arc::new(Reloader {
+        state: app_state.clone(),
+        md5s: md5s_tx,
+        roots: roots_tx,
+        announce_all,
+        dump_path,
+    });
+
+    let watcher = if watch {
+        let reloader = reloader.clone();
+        Some(Arc::from(
+            DirWatcher::new(&app_state.pathes, WATCH_DEBOUNCE, move || {
+                let reloader = reloader.clone();
+                async move {
+                    if let Err(err) = reloader.reload(HashCache::new()).await {
+                        eprintln!("Failed to reload: {:?}", err);
+                    }
+                }
+            })
+            .map_err(std::io::Error::other)?,
+        ))
+    } else {
+        None
+    };
+    let seeding = match seed_dir {
+        // any tree in it could be served, so only holders of the token may
+        Some(_) if credentials.token.is_none() => {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "--seed-dir needs --token or --token-file",
+            ));
+        }
+        Some(dir) => Some(Arc::new(Seeding {
+            reloader,
+            watcher: watcher.clone(),
+            bound: address.parse().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            dir: std::fs::canonicalize(dir)?,
+        })),
+        None => None,
+    };
+
+    let tag_publisher = match tag {
+        Some(name) => {
+            let roots = app_state.announced_md5s(false).await.len();
+            if roots != 1 {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("--tag needs exactly one served root, {} are served", roots),
+                ));
+            }
+            Some(spawn_tag_publisher(
+                name,
+                app_state.clone(),
+                tracker.clone(),
+                &credentials,
+            )?)
+        }
+        None => None,
+    };
 
-    let app = build_app(app_state);
+    let mut app = build_app(app_state, manifests, seeding, credentials.token.clone());
 
     let addr = format!("{}:{}", address, port);
-    let listener = TcpListener::bind(&addr).await?;
+    let listener = ServerListener::bind(&addr, tls.as_ref()).await?;
+    let self_url = format!("{}://{}", tls::scheme(tls.as_ref()), addr);
+
+    let mut publisher = None;
+    if let Some(bootstrap) = dht {
+        let dht = Arc::new(Dht::node(self_url.clone(), bootstrap).with_credentials(&credentials));
+        app = app.merge(dht.clone().router());
+        publisher = Some(dht.spawn_publisher(md5s_rx.clone(), Duration::from_secs(30)));
+    }
 
-    let heart_beater =
-        HeartBeater::new(format!("http://{}", addr), tracker, Duration::from_secs(30));
+    let lan_announcer = if lan {
+        // announce through the interface of the bind address when it has one
+        let interface = address.parse().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        Some(lan::spawn_announcer(
+            LAN_GROUP,
+            interface,
+            self_url.clone(),
+            roots_rx,
+            ANNOUNCE_INTERVAL,
+        )?)
+    } else {
+        None
+    };
 
-    println!("Listening on http://{}", addr);
+    let mut heart_beater = HeartBeater::new(
+        self_url.clone(),
+        tracker,
+        Duration::from_secs(30),
+        md5s_rx,
+        &credentials,
+    );
 
-    axum::serve(listener, app).await?;
+    println!("Listening on {}", self_url);
 
-    heart_beater.stop();
+    let (stopping_tx, mut stopping_rx) = watch::channel(false);
+    let app = app.into_make_service_with_connect_info::<PeerAddr>();
+    let serve = axum::serve(listener, app).with_graceful_shutdown(async move {
+        let _ = stopping_rx.wait_for(|stopping| *stopping).await;
+    });
+    tokio::select! {
+        result = serve.into_future() => result?,
+        _ = async {
+            shutdown_signal().await;
+            // leave the trackers, the DHT and the lan first, so no new
+            // downloader is sent here while transfers drain
+            eprintln!("Shutting down, deregistering from the trackers");
+            heart_beater.deregister().await;
+            if let Some(publisher) = publisher {
+                publisher.abort();
+            }
+            if let Some(lan_announcer) = lan_announcer {
+                lan_announcer.abort();
+            }
+            if let Some(tag_publisher) = tag_publisher {
+                tag_publisher.abort();
+            }
+            eprintln!("Waiting up to {:?} for transfers to finish", drain_timeout);
+            stopping_tx.send_replace(true);
+            tokio::time::sleep(drain_timeout).await;
+        } => eprintln!("Transfers still running after {:?}, exiting", drain_timeout),
+    }
+
+    if let Some(watcher) = watcher {
+        watcher.stop();
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AppState, BaselineLoadItem, Reloader, SeedRequest, SeedResponse, Seeding, build_app,
+    };
+    use crate::hash::HashAlgo;
+    use crate::manifest::{Manifests, SignedManifest, SigningKey};
+    use crate::server::{FileStamp, HashCache, LookupDirOrFile};
+    use crate::tls::{PeerAddr, ServerListener};
+    use reqwest::{StatusCode, header};
+    use std::io;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    async fn start_server(data: &[u8]) -> io::Result<(tempfile::TempDir, String)> {
+        start_server_with(data, Manifests::default()).await
+    }
+
+    async fn start_server_with(
+        data: &[u8],
+        manifests: Manifests,
+    ) -> io::Result<(tempfile::TempDir, String)> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("data.bin"), data)?;
+        let state = AppState::new(
+            vec![dir.path().to_string_lossy().into_owned()],
+            None,
+            HashAlgo::Md5,
+        )?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = build_app(Arc::new(state), Arc::new(manifests), None, None);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = format!("http://{}/download?md5={:x}", addr, md5::compute(data));
+        Ok((dir, url))
+    }
+
+    #[tokio::test]
+    async fn test_download_ranges() -> Result<(), Box<dyn std::error::Error>> {
+        let data = (0..=255u8).collect::<Vec<_>>();
+        let (_dir, url) = start_server(&data).await?;
+        let client = reqwest::Client::new();
+        let etag = format!("\"{:x}\"", md5::compute(&data));
+
+        let resp = client.get(&url).send().await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(resp.headers()[header::ETAG], etag.as_str());
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "256");
+        assert_eq!(resp.bytes().await?.as_ref(), data.as_slice());
+
+        let resp = client
+            .get(&url)
+            .header(header::RANGE, "bytes=10-19")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 10-19/256");
+        assert_eq!(resp.bytes().await?.as_ref(), &data[10..20]);
+
+        let resp = client
+            .get(&url)
+            .header(header::RANGE, "bytes=0-1,-2")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let length = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()?
+            .parse::<usize>()?;
+        let body = resp.bytes().await?;
+        assert_eq!(body.len(), length);
+        let windows = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(windows(b"Content-Range: bytes 0-1/256\r\n\r\n\x00\x01"));
+        assert!(windows(b"Content-Range: bytes 254-255/256\r\n\r\n\xfe\xff"));
+
+        let resp = client
+            .get(&url)
+            .header(header::RANGE, "bytes=300-")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */256");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_conditional() -> Result<(), Box<dyn std::error::Error>> {
+        let data = b"conditional".to_vec();
+        let (_dir, url) = start_server(&data).await?;
+        let client = reqwest::Client::new();
+        let etag = format!("\"{:x}\"", md5::compute(&data));
+
+        let resp = client
+            .get(&url)
+            .header(header::IF_NONE_MATCH, etag.as_str())
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        let resp = client
+            .get(&url)
+            .header(header::RANGE, "bytes=0-3")
+            .header(header::IF_RANGE, etag.as_str())
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.bytes().await?.as_ref(), b"cond");
+
+        let resp = client
+            .get(&url)
+            .header(header::RANGE, "bytes=0-3")
+            .header(header::IF_RANGE, "\"stale\"")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.bytes().await?.as_ref(), data.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_rehashes_stale_dump() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir(data_dir.as_path())?;
+        std::fs::write(data_dir.join("a.txt"), b"a")?;
+        let cache_path = dir.path().join("hash.cache");
+        let dump_path = dir.path().join("spec.bin");
+
+        let state = AppState::new(
+            vec![data_dir.to_string_lossy().into_owned()],
+            Some(cache_path.clone()),
+            HashAlgo::Md5,
+        )?;
+        state
+            .dump(std::fs::File::create(dump_path.as_path())?)
+            .await?;
+        assert!(cache_path.exists());
+
+        std::fs::write(data_dir.join("a.txt"), b"changed")?;
+        let state = AppState::load_from_binary(
+            dump_path.to_string_lossy().into_owned(),
+            Some(cache_path.clone()),
+            None,
+        )?;
+        let vfs = state.vfs.read().await;
+        assert!(vfs.lookup(&format!("{:x}", md5::compute(b"a"))).is_none());
+        assert!(
+            vfs.lookup(&format!("{:x}", md5::compute(b"changed")))
+                .is_some()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_migrates_legacy_dump() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir(data_dir.as_path())?;
+        std::fs::write(data_dir.join("a.txt"), b"a")?;
+        let dump_path = dir.path().join("spec.bin");
+
+        let state = AppState::new(
+            vec![data_dir.to_string_lossy().into_owned()],
+            None,
+            HashAlgo::Md5,
+        )?;
+        let mut expected = Vec::new();
+        {
+            let vfs = state.vfs.read().await;
+            vfs.dump_md5(&mut expected)?;
+            // v2 and v3 dumps have no hash field
+            #[derive(serde::Serialize)]
+            struct LegacyDumpItem<'a> {
+                format: &'a str,
+                vfs: &'a crate::server::fs::VirtualFileSystem,
+                pathes: &'a Vec<std::path::PathBuf>,
+            }
+            let legacy = LegacyDumpItem {
+                format: "p2psync-spec-v2",
+                vfs: vfs.as_ref(),
+                pathes: &state.pathes,
+            };
+            let data =
+                serde_binary::to_vec(&legacy, super::Endian::Little).map_err(io::Error::other)?;
+            std::fs::write(dump_path.as_path(), data)?;
+        }
+
+        let state =
+            AppState::load_from_binary(dump_path.to_string_lossy().into_owned(), None, None)?;
+        let mut loaded = Vec::new();
+        state.vfs.read().await.dump_md5(&mut loaded)?;
+        assert_eq!(loaded, expected);
+
+        // a dump cut short is neither layout
+        let data = std::fs::read(dump_path.as_path())?;
+        std::fs::write(dump_path.as_path(), &data[..data.len() / 2])?;
+        assert!(
+            AppState::load_from_binary(dump_path.to_string_lossy().into_owned(), None, None)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_baseline_dump() -> io::Result<()> {
+        // written by `serve --path src/server/testdata/baseline-tree
+        // --dump-path src/server/testdata/baseline.spec` before dumps had a
+        // format header
+        let dump_path = "src/server/testdata/baseline.spec";
+        let data = std::fs::read(dump_path)?;
+        let item: BaselineLoadItem =
+            serde_binary::from_slice(&data, super::Endian::Little).map_err(io::Error::other)?;
+        let a = std::path::Path::new("src/server/testdata/baseline-tree/a.txt");
+        let a_md5 = "9f9f90dbe3e5ee1218c86b8839db1995";
+        // file md5s are taken from the dump, not hashed again
+        let dumped = item.vfs.hash_cache(i64::MAX);
+        assert_eq!(dumped.len(), 2);
+        assert_eq!(dumped.get(&FileStamp::from_path(a)?), Some(a_md5));
+
+        let state = AppState::load_from_binary(dump_path.to_string(), None, None)?;
+        let vfs = state.vfs.read().await;
+        assert_eq!(vfs.algo(), HashAlgo::Md5);
+        assert_eq!(vfs.file_path(a_md5)?, a);
+        // the directory md5 is the current digest, not the dumped one
+        let scanned = AppState::new(
+            vec!["src/server/testdata/baseline-tree".to_string()],
+            None,
+            HashAlgo::Md5,
+        )?;
+        let root_md5s = vfs.root_md5s();
+        assert_eq!(root_md5s, scanned.vfs.read().await.root_md5s());
+        assert_ne!(root_md5s, vec!["e1433d63c6c277db90046d9fbcecfae5"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_baseline_dump_rehashes_rewritten_files() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir(&data_dir)?;
+        let (a, b) = (data_dir.join("a.bin"), data_dir.join("b.bin"));
+        std::fs::write(&a, b"aaaa")?;
+        std::fs::write(&b, b"bbbb")?;
+        let md5 = |data: &[u8]| format!("{:x}", md5::compute(data));
+        let file = |path: &std::path::Path, data: &[u8]| {
+            serde_json::json!({
+                "path": path,
+                "md5": md5(data),
+                "special_fields": {"File": {"size": data.len()}},
+            })
+        };
+        let item: BaselineLoadItem = serde_json::from_value(serde_json::json!({
+            "vfs": {"items": [file(&a, b"aaaa"), file(&b, b"bbbb")], "md5_to_id": {}},
+            "pathes": [data_dir],
+        }))
+        .map_err(io::Error::other)?;
+        let dump_path = dir.path().join("spec.bin");
+        std::fs::write(
+            &dump_path,
+            serde_binary::to_vec(&item, super::Endian::Little).map_err(io::Error::other)?,
+        )?;
+        let now = std::time::SystemTime::now();
+        let set_mtime = |path: &std::path::Path, ago: u64| -> io::Result<()> {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(now - std::time::Duration::from_secs(ago))
+        };
+        set_mtime(&a, 20)?;
+        set_mtime(&dump_path, 10)?;
+        // rewritten after the dump, with the same size
+        std::fs::write(&b, b"cccc")?;
+
+        let state = AppState::load_from_binary(dump_path.to_string_lossy().into(), None, None)?;
+        let vfs = state.vfs.read().await;
+        assert_eq!(vfs.file_path(&md5(b"aaaa"))?, a);
+        assert_eq!(vfs.file_path(&md5(b"cccc"))?, b);
+        assert!(vfs.file_path(&md5(b"bbbb")).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dump_keeps_hash_algo() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir(data_dir.as_path())?;
+        std::fs::write(data_dir.join("a.txt"), b"a")?;
+        let dump_path = dir.path().join("spec.bin");
+
+        let state = AppState::new(
+            vec![data_dir.to_string_lossy().into_owned()],
+            None,
+            HashAlgo::Blake3,
+        )?;
+        state
+            .dump(std::fs::File::create(dump_path.as_path())?)
+            .await?;
+
+        let state =
+            AppState::load_from_binary(dump_path.to_string_lossy().into_owned(), None, None)?;
+        let vfs = state.vfs.read().await;
+        assert_eq!(vfs.algo(), HashAlgo::Blake3);
+        assert!(vfs.lookup(&HashAlgo::Blake3.id_of(b"a")).is_some());
+        assert!(vfs.lookup(&HashAlgo::Md5.id_of(b"a")).is_none());
+        drop(vfs);
+
+        // an explicit algorithm re-hashes the dumped files
+        let state = AppState::load_from_binary(
+            dump_path.to_string_lossy().into_owned(),
+            None,
+            Some(HashAlgo::Sha256),
+        )?;
+        let vfs = state.vfs.read().await;
+        assert!(vfs.lookup(&HashAlgo::Sha256.id_of(b"a")).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manifest() -> Result<(), Box<dyn std::error::Error>> {
+        let keys = tempfile::tempdir()?;
+        let key_path = keys.path().join("publisher.key");
+        let public_key = SigningKey::generate(&key_path)?;
+        let data = b"signed content";
+        let (_dir, url) = start_server_with(data, Manifests::load(Some(&key_path), &[])?).await?;
+        let url = url.replace("/download", "/manifest");
+
+        let signed = reqwest::get(&url)
+            .await?
+            .error_for_status()?
+            .json::<SignedManifest>()
+            .await?;
+        let manifest = signed.verify(&public_key)?;
+        assert_eq!(manifest.md5, format!("{:x}", md5::compute(data)));
+        assert_eq!(
+            manifest.tree,
+            LookupDirOrFile::File {
+                name: "data.bin".to_string(),
+                md5: manifest.md5.clone(),
+                size: data.len(),
+            }
+        );
+
+        let missing = url.replace(&manifest.md5, "missing");
+        assert_eq!(reqwest::get(missing).await?.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_roots_apart() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a.txt"), b"a")?;
+        let state = Arc::new(AppState::new(
+            vec![dir.path().to_string_lossy().into_owned()],
+            None,
+            HashAlgo::Md5,
+        )?);
+        let (md5s_tx, md5s_rx) = watch::channel(Vec::new());
+        let (roots_tx, roots_rx) = watch::channel(Vec::new());
+        let reloader = Reloader {
+            state: state.clone(),
+            md5s: md5s_tx,
+            roots: roots_tx,
+            announce_all: true,
+            dump_path: None,
+        };
+        reloader.reload(HashCache::new()).await?;
+
+        // every id goes to the trackers, only the root to the lan
+        let root = state.vfs.read().await.root_md5s();
+        assert_eq!(md5s_rx.borrow().len(), 2);
+        assert_eq!(*roots_rx.borrow(), root);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_seed() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let served = dir.path().join("served");
+        std::fs::create_dir(&served)?;
+        std::fs::create_dir(dir.path().join("seeds"))?;
+        std::fs::write(served.join("a.txt"), b"a")?;
+        let state = Arc::new(AppState::new(
+            vec![served.to_string_lossy().into_owned()],
+            None,
+            HashAlgo::Md5,
+        )?);
+        let (md5s_tx, mut md5s_rx) = watch::channel(Vec::new());
+        let seeding = Arc::new(Seeding {
+            reloader: Arc::new(Reloader {
+                state: state.clone(),
+                md5s: md5s_tx,
+                roots: watch::channel(Vec::new()).0,
+                announce_all: false,
+                dump_path: None,
+            }),
+            watcher: None,
+            bound: "127.0.0.1".parse()?,
+            dir: std::fs::canonicalize(dir.path().join("seeds"))?,
+        });
+        let listener = ServerListener::bind("127.0.0.1:0", None).await?;
+        let addr = axum::serve::Listener::local_addr(&listener)?;
+        let app = build_app(
+            state.clone(),
+            Arc::default(),
+            Some(seeding),
+            Some("secret".to_string()),
+        )
+        .into_make_service_with_connect_info::<PeerAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let downloaded = dir.path().join("seeds/downloaded");
+        std::fs::create_dir(&downloaded)?;
+        std::fs::write(downloaded.join("b.txt"), b"b")?;
+        let verified = "0123456789abcdef0123456789abcdef".to_string();
+        let stamp = FileStamp::from_path(&downloaded.join("b.txt"))?;
+        let client = reqwest::Client::new();
+        let post_seed = |path: PathBuf, files: Vec<(FileStamp, String)>| {
+            client
+                .post(format!("http://{}/seed", addr))
+                .bearer_auth("secret")
+                .json(&SeedRequest { path, files })
+                .send()
+        };
+
+        // ids are only taken for the files of the tree itself
+        let outside = dir.path().join("c.txt");
+        std::fs::write(&outside, b"c")?;
+        std::os::unix::fs::symlink(&outside, downloaded.join("c.txt"))?;
+        let forged = "fedcba9876543210fedcba9876543210".to_string();
+        let outside_stamp = FileStamp::from_path(&outside)?;
+
+        // only holders of the token may seed
+        let anonymous = client
+            .post(format!("http://{}/seed", addr))
+            .json(&SeedRequest {
+                path: downloaded.clone(),
+                files: Vec::new(),
+            })
+            .send()
+            .await?;
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+
+        // the id the downloader verified is taken as it is, not hashed again
+        let seeded = post_seed(
+            downloaded.clone(),
+            vec![(stamp, verified.clone()), (outside_stamp, forged.clone())],
+        )
+        .await?
+        .error_for_status()?
+        .json::<SeedResponse>()
+        .await?;
+        let resp = client
+            .get(format!("http://{}/download?md5={}", addr, verified))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(resp.bytes().await?.as_ref(), b"b");
+        let forged = client
+            .get(format!("http://{}/download?md5={}", addr, forged))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(forged.status(), StatusCode::NOT_FOUND);
+        let c = format!("{:x}", md5::compute(b"c"));
+        let resp = client
+            .get(format!("http://{}/download?md5={}", addr, c))
+            .bearer_auth("secret")
+            .send()
+            .await?;
+        assert_eq!(resp.bytes().await?.as_ref(), b"c");
+        assert!(md5s_rx.has_changed()?);
+        assert!(md5s_rx.borrow_and_update().contains(&seeded.md5));
+
+        let missing = post_seed(dir.path().join("seeds/missing"), Vec::new()).await?;
+        assert_eq!(missing.status(), StatusCode::BAD_REQUEST);
+        // nothing outside the seed directory is served
+        let outside = post_seed(dir.path().join("seeds/../served"), Vec::new()).await?;
+        assert_eq!(outside.status(), StatusCode::FORBIDDEN);
+        assert_eq!(state.vfs.read().await.root_md5s().len(), 2);
+
+        // a seeded root that is removed is no longer served
+        std::fs::remove_dir_all(&downloaded)?;
+        state.reload(HashCache::new()).await?;
+        assert_eq!(state.vfs.read().await.root_md5s().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_is_local_only() -> Result<(), Box<dyn std::error::Error>> {
+        let seeding = |bound: &str| -> Result<Seeding, Box<dyn std::error::Error>> {
+            let state = AppState::new(Vec::new(), None, HashAlgo::Md5)?;
+            Ok(Seeding {
+                reloader: Arc::new(Reloader {
+                    state: Arc::new(state),
+                    md5s: watch::channel(Vec::new()).0,
+                    roots: watch::channel(Vec::new()).0,
+                    announce_all: false,
+                    dump_path: None,
+                }),
+                watcher: None,
+                bound: bound.parse()?,
+                dir: PathBuf::from("/"),
+            })
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let bound = seeding("10.0.0.1")?;
+        assert!(bound.is_local(ip("127.0.0.1")));
+        assert!(bound.is_local(ip("10.0.0.1")));
+        assert!(!bound.is_local(ip("10.0.0.2")));
+        let unspecified = seeding("0.0.0.0")?;
+        assert!(unspecified.is_local(ip("::1")));
+        assert!(!unspecified.is_local(ip("10.0.0.2")));
+        Ok(())
+    }
+}
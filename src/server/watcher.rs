@@ -0,0 +1,105 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+fn watch(watcher: &mut RecommendedWatcher, path: &Path) -> notify::Result<()> {
+    // served roots are usually symlinks, watch what they point to
+    let path = std::fs::canonicalize(path)?;
+    watcher.watch(path.as_path(), RecursiveMode::Recursive)
+}
+
+/// Watches the served roots and calls `on_change` once the file system has
+/// been quiet for `debounce` after a modification.
+pub struct DirWatcher {
+    watcher: Mutex<RecommendedWatcher>,
+    handle: JoinHandle<()>,
+}
+
+impl DirWatcher {
+    pub fn new<F, Fut>(
+        pathes: &[PathBuf],
+        debounce: Duration,
+        on_change: F,
+    ) -> notify::Result<Box<Self>>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+                // reading a file (including our own hashing) is not a change
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                Ok(_) => {
+                    let _ = tx.send(());
+                }
+                Err(err) => eprintln!("watch error: {:?}", err),
+            })?;
+
+        for path in pathes.iter() {
+            watch(&mut watcher, path)?;
+        }
+
+        let handle = tokio::spawn(async move {
+            while rx.recv().await.is_some() {
+                // wait until no event arrives for a whole debounce window
+                while let Ok(Some(())) = tokio::time::timeout(debounce, rx.recv()).await {}
+                on_change().await;
+            }
+        });
+
+        Ok(Box::new(DirWatcher {
+            watcher: Mutex::new(watcher),
+            handle,
+        }))
+    }
+
+    /// Watches a root added after the watcher was created.
+    pub fn watch(&self, path: &Path) -> notify::Result<()> {
+        watch(&mut self.watcher.lock().unwrap(), path)
+    }
+
+    pub fn stop(&self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirWatcher;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_change_is_debounced() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let watcher = DirWatcher::new(
+            &[dir.path().to_path_buf()],
+            Duration::from_millis(200),
+            move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+        )
+        .map_err(std::io::Error::other)?;
+
+        for i in 0..5 {
+            std::fs::write(dir.path().join(format!("{}.txt", i)), b"data")?;
+        }
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        watcher.stop();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
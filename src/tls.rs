@@ -0,0 +1,313 @@
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig, crypto};
+use tokio_rustls::server::TlsStream;
+use tokio_util::either::Either;
+
+/// How long a client may take to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections that completed the handshake and wait to be served.
+const HANDSHAKE_BACKLOG: usize = 64;
+
+/// Certificate and key a tracker or server presents. With `client_ca`,
+/// clients must present a certificate signed by that CA (mutual TLS).
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+fn invalid_pem(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), err),
+    )
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path)?;
+    CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| invalid_pem(path, err))
+}
+
+impl ServerTls {
+    fn config(&self) -> io::Result<Arc<ServerConfig>> {
+        let certs = read_certs(&self.cert)?;
+        let key = PrivateKeyDer::from_pem_slice(&std::fs::read(&self.key)?)
+            .map_err(|err| invalid_pem(&self.key, err))?;
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let builder = match self.client_ca.as_ref() {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots.add(cert).map_err(|err| invalid_pem(client_ca, err))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .map_err(io::Error::other)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|err| invalid_pem(&self.cert, err))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+/// URL scheme of the peers served with `tls`.
+pub fn scheme(tls: Option<&ServerTls>) -> &'static str {
+    if tls.is_some() { "https" } else { "http" }
+}
+
+/// A TCP listener that optionally terminates TLS. Handshakes run in their own
+/// tasks, so a slow client does not hold up the others.
+pub enum ServerListener {
+    Plain(TcpListener),
+    Tls {
+        local_addr: SocketAddr,
+        handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+        accepting: JoinHandle<()>,
+    },
+}
+
+impl ServerListener {
+    pub async fn bind(addr: impl ToSocketAddrs, tls: Option<&ServerTls>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let Some(tls) = tls else {
+            return Ok(ServerListener::Plain(listener));
+        };
+
+        let acceptor = TlsAcceptor::from(tls.config()?);
+        let local_addr = listener.local_addr()?;
+        let (tx, handshaken) = mpsc::channel(HANDSHAKE_BACKLOG);
+        let mut listener = listener;
+        let accepting = tokio::spawn(async move {
+            loop {
+                let (stream, addr) = axum::serve::Listener::accept(&mut listener).await;
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(err)) => eprintln!("TLS handshake with {} failed: {}", addr, err),
+                        Err(_) => eprintln!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(ServerListener::Tls {
+            local_addr,
+            handshaken,
+            accepting,
+        })
+    }
+}
+
+impl Drop for ServerListener {
+    fn drop(&mut self) {
+        if let ServerListener::Tls { accepting, .. } = self {
+            accepting.abort();
+        }
+    }
+}
+
+impl axum::serve::Listener for ServerListener {
+    type Io = Either<TcpStream, TlsStream<TcpStream>>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self {
+            ServerListener::Plain(listener) => {
+                let (stream, addr) = axum::serve::Listener::accept(listener).await;
+                (Either::Left(stream), addr)
+            }
+            ServerListener::Tls { handshaken, .. } => {
+                // the sender lives as long as the accepting task, which only
+                // stops when this listener is dropped
+                let (stream, addr) = handshaken.recv().await.expect("TLS accept loop stopped");
+                (Either::Right(stream), addr)
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        match self {
+            ServerListener::Plain(listener) => listener.local_addr(),
+            ServerListener::Tls { local_addr, .. } => Ok(*local_addr),
+        }
+    }
+}
+
+/// Address of the client of a connection accepted by `ServerListener`, for
+/// handlers that extract `ConnectInfo<PeerAddr>`.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, ServerListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, ServerListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Credentials;
+    use axum::{Router, routing::get};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509, X509NameBuilder};
+
+    fn issue(
+        name: &str,
+        serial: u32,
+        ca: Option<(&X509, &PKey<Private>)>,
+    ) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        match ca {
+            Some((ca_cert, ca_key)) => {
+                builder.set_issuer_name(ca_cert.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .ip("127.0.0.1")
+                    .dns("localhost")
+                    .build(&builder.x509v3_context(Some(ca_cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&subject).unwrap();
+                let constraints = BasicConstraints::new().critical().ca().build().unwrap();
+                builder.append_extension(constraints).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    /// PEM files of a CA, and of a server and a client certificate it signed.
+    struct TestPki {
+        dir: tempfile::TempDir,
+    }
+
+    impl TestPki {
+        fn generate() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let (ca_cert, ca_key) = issue("p2psync test ca", 1, None);
+            let write = |name: &str, (cert, key): (X509, PKey<Private>)| {
+                std::fs::write(
+                    dir.path().join(format!("{}.pem", name)),
+                    cert.to_pem().unwrap(),
+                )
+                .unwrap();
+                std::fs::write(
+                    dir.path().join(format!("{}.key", name)),
+                    key.private_key_to_pem_pkcs8().unwrap(),
+                )
+                .unwrap();
+            };
+            write("server", issue("server", 2, Some((&ca_cert, &ca_key))));
+            write("client", issue("client", 3, Some((&ca_cert, &ca_key))));
+            write("ca", (ca_cert, ca_key));
+            TestPki { dir }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join(name)
+        }
+
+        fn server_tls(&self, mutual: bool) -> ServerTls {
+            ServerTls {
+                cert: self.path("server.pem"),
+                key: self.path("server.key"),
+                client_ca: mutual.then(|| self.path("ca.pem")),
+            }
+        }
+
+        fn credentials(&self, identity: bool) -> Credentials {
+            let (cert, key) = (self.path("client.pem"), self.path("client.key"));
+            Credentials::default()
+                .with_tls(
+                    Some(&self.path("ca.pem")),
+                    identity.then_some((cert.as_path(), key.as_path())),
+                )
+                .unwrap()
+        }
+    }
+
+    async fn serve(tls: &ServerTls) -> String {
+        let listener = ServerListener::bind("127.0.0.1:0", Some(tls))
+            .await
+            .unwrap();
+        let addr = axum::serve::Listener::local_addr(&listener).unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("https://{}", addr)
+    }
+
+    async fn get_with(credentials: &Credentials, url: &str) -> reqwest::Result<String> {
+        let client = credentials.client_builder().build()?;
+        client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_tls_and_mutual_tls() {
+        let pki = TestPki::generate();
+
+        let url = serve(&pki.server_tls(false)).await;
+        assert_eq!(get_with(&pki.credentials(false), &url).await.unwrap(), "ok");
+        // the test CA is not trusted by default
+        assert!(get_with(&Credentials::default(), &url).await.is_err());
+
+        let url = serve(&pki.server_tls(true)).await;
+        assert_eq!(get_with(&pki.credentials(true), &url).await.unwrap(), "ok");
+        assert!(get_with(&pki.credentials(false), &url).await.is_err());
+    }
+}
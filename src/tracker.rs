@@ -1,14 +1,21 @@
+use crate::auth::{self, Credentials};
+use crate::tls::{self, ServerListener, ServerTls};
 use axum::{
     Router,
-    extract::{Json, State},
-    response::{IntoResponse, Json as ResponseJson},
+    extract::{Json, Path as UrlPath, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Json as ResponseJson, Response},
     routing::{get, post},
 };
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use reqwest::Method;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_binary::binary_stream::Endian;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
 /// Peer information stored by the tracker
@@ -16,12 +23,32 @@ use tokio::sync::RwLock;
 pub struct PeerInfo {
     pub addr: String,
     pub last_seen: u64, // Unix timestamp
+    /// Restored from the tracker's state file and not announced since.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unconfirmed: bool,
+    /// Failed the last health probe of the tracker, even if it still
+    /// announces itself.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unhealthy: bool,
 }
 
 /// Request to announce a peer
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AnnounceRequest {
     pub addr: String,
+    /// Content ids the peer serves. Peers that leave it out are assumed to
+    /// serve everything, like before content ids were announced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5s: Option<Vec<String>>,
+    /// Relayed by another tracker, so it is not relayed any further.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub relayed: bool,
+}
+
+/// Query of `GET /peers`, without `md5` every peer is returned
+#[derive(Debug, Deserialize)]
+pub struct PeersQuery {
+    pub md5: Option<String>,
 }
 
 /// Response containing list of peers
@@ -30,88 +57,634 @@ pub struct PeersResponse {
     pub peers: Vec<PeerInfo>,
 }
 
+/// Every peer a tracker knows with the content ids it announced, and every
+/// tag with its history, sent to sibling trackers that catch up after a
+/// restart
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicaResponse {
+    pub peers: Vec<(PeerInfo, Option<Vec<String>>)>,
+    #[serde(default)]
+    pub tags: Vec<(String, Vec<TagVersion>)>,
+}
+
+/// A content id a tag pointed to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TagVersion {
+    pub md5: String,
+    pub published_at: u64, // Unix timestamp
+}
+
+/// Request to point a tag at a content id
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PublishTagRequest {
+    pub md5: String,
+    /// When the first tracker published it, so siblings record the same time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<u64>,
+    /// Relayed by another tracker, so it is not relayed any further.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub relayed: bool,
+}
+
+/// The content id a tag points to
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagResponse {
+    pub name: String,
+    pub md5: String,
+    pub published_at: u64,
+}
+
+/// Every tag with the content id it points to
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagsResponse {
+    pub tags: Vec<TagResponse>,
+}
+
+/// Every content id a tag pointed to, oldest first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagHistoryResponse {
+    pub name: String,
+    pub versions: Vec<TagVersion>,
+}
+
+/// Versions kept per tag, older ones are forgotten.
+const MAX_TAG_HISTORY: usize = 100;
+
+/// Tag names are letters, digits and `.`, `_`, `-` or `:`, such as
+/// `llama-70b:latest`, so they can be used in URLs as they are.
+pub fn valid_tag_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | ':'))
+}
+
 /// Standard API response format
 #[derive(Debug, Serialize)]
 pub struct ApiResponse {
     pub status: String,
 }
 
-/// Tracker state - stores information about connected peers
+/// Connected peers and the content ids each of them announced
+#[derive(Debug, Default)]
+struct Peers {
+    info: HashMap<String, PeerInfo>,
+    // peers that announced their content ids -> those ids
+    held: HashMap<String, HashSet<String>>,
+    // content id -> peers that announced it
+    holders: HashMap<String, HashSet<String>>,
+}
+
+impl Peers {
+    fn insert(&mut self, peer: PeerInfo, md5s: Option<Vec<String>>) {
+        let addr = peer.addr.clone();
+        self.remove(addr.as_str());
+        if let Some(md5s) = md5s {
+            for md5 in md5s.iter() {
+                self.holders
+                    .entry(md5.clone())
+                    .or_default()
+                    .insert(addr.clone());
+            }
+            self.held.insert(addr.clone(), md5s.into_iter().collect());
+        }
+        self.info.insert(addr, peer);
+    }
+
+    fn remove(&mut self, addr: &str) {
+        self.info.remove(addr);
+        for md5 in self.held.remove(addr).unwrap_or_default() {
+            if let Some(holders) = self.holders.get_mut(&md5) {
+                holders.remove(addr);
+                if holders.is_empty() {
+                    self.holders.remove(&md5);
+                }
+            }
+        }
+    }
+
+    /// Peers that announced `md5`, and peers that announced no ids at all.
+    fn holding(&self, md5: &str) -> Vec<PeerInfo> {
+        let holders = self.holders.get(md5);
+        self.info
+            .values()
+            .filter(|peer| {
+                !self.held.contains_key(&peer.addr)
+                    || holders.is_some_and(|holders| holders.contains(&peer.addr))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+const STATE_FORMAT: &str = "p2psync-tracker-state-v1";
+
+/// On-disk snapshot of the tracker's peers
+#[derive(Debug, Serialize, Deserialize)]
+struct StateFile {
+    format: String,
+    peers: Vec<(PeerInfo, Option<Vec<String>>)>,
+    #[serde(default)]
+    tags: Vec<(String, Vec<TagVersion>)>,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Tracker state - stores information about connected peers and the tags
+/// published to it
 #[derive(Debug)]
 pub struct TrackerState {
-    peers: RwLock<HashMap<String, PeerInfo>>,
+    peers: RwLock<Peers>,
+    // tag name -> versions, oldest first
+    tags: RwLock<HashMap<String, Vec<TagVersion>>>,
+}
+
+impl Default for TrackerState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TrackerState {
     pub fn new() -> Self {
         Self {
-            peers: RwLock::new(HashMap::new()),
+            peers: RwLock::new(Peers::default()),
+            tags: RwLock::new(HashMap::new()),
         }
     }
 
-    /// Add or update a peer in the tracker
-    pub async fn announce_peer(&self, peer: PeerInfo) {
+    /// Add or update a peer in the tracker, replacing the content ids it
+    /// announced before
+    pub async fn announce_peer(&self, mut peer: PeerInfo, md5s: Option<Vec<String>>) {
         let mut peers = self.peers.write().await;
-        peers.insert(peer.addr.clone(), peer);
+        // only a probe decides that a peer is healthy again
+        peer.unhealthy = peers
+            .info
+            .get(&peer.addr)
+            .is_some_and(|known| known.unhealthy);
+        peers.insert(peer, md5s);
+    }
+
+    /// Records the result of a health probe of `addr`.
+    pub async fn set_healthy(&self, addr: &str, healthy: bool) {
+        let mut peers = self.peers.write().await;
+        if let Some(peer) = peers.info.get_mut(addr)
+            && peer.unhealthy == healthy
+        {
+            eprintln!(
+                "peer {} is {}",
+                addr,
+                if healthy {
+                    "healthy again"
+                } else {
+                    "unhealthy"
+                }
+            );
+            peer.unhealthy = !healthy;
+        }
+    }
+
+    /// Forget a peer that is shutting down
+    pub async fn remove_peer(&self, addr: &str) {
+        let mut peers = self.peers.write().await;
+        peers.remove(addr);
     }
 
     /// Get all active peers
     pub async fn get_peers(&self) -> Vec<PeerInfo> {
         let peers = self.peers.read().await;
-        peers.values().cloned().collect()
+        peers.info.values().cloned().collect()
+    }
+
+    /// Get the active peers that may serve `md5`
+    pub async fn get_peers_holding(&self, md5: &str) -> Vec<PeerInfo> {
+        let peers = self.peers.read().await;
+        peers.holding(md5)
+    }
+
+    /// Every peer with the content ids it announced.
+    pub async fn entries(&self) -> Vec<(PeerInfo, Option<Vec<String>>)> {
+        let peers = self.peers.read().await;
+        peers
+            .info
+            .values()
+            .map(|peer| {
+                let md5s = peers
+                    .held
+                    .get(&peer.addr)
+                    .map(|md5s| md5s.iter().cloned().collect());
+                (peer.clone(), md5s)
+            })
+            .collect()
+    }
+
+    /// Adds the entries of another tracker, keeping whichever side saw a
+    /// peer last. Times ahead of the local clock are taken as now.
+    pub async fn merge(&self, entries: Vec<(PeerInfo, Option<Vec<String>>)>) {
+        let current_time = now();
+        let mut peers = self.peers.write().await;
+        for (mut peer, md5s) in entries.into_iter() {
+            peer.last_seen = peer.last_seen.min(current_time);
+            if peers
+                .info
+                .get(&peer.addr)
+                .is_none_or(|known| known.last_seen < peer.last_seen)
+            {
+                peers.insert(peer, md5s);
+            }
+        }
+    }
+
+    /// Points tag `name` at `version.md5` and returns the version it points
+    /// to. Publishing the id the tag already points to changes nothing. The
+    /// history is kept in `published_at` order, so a version relayed late by
+    /// a sibling goes into the history without moving the tag back to it.
+    pub async fn publish_tag(&self, name: &str, version: TagVersion) -> TagVersion {
+        let mut tags = self.tags.write().await;
+        let versions = tags.entry(name.to_string()).or_default();
+        let at = versions.partition_point(|known| known.published_at <= version.published_at);
+        if at == 0 || versions[at - 1].md5 != version.md5 {
+            versions.insert(at, version.clone());
+            if versions.len() > MAX_TAG_HISTORY {
+                versions.remove(0);
+            }
+        }
+        versions.last().cloned().unwrap_or(version)
+    }
+
+    /// The version tag `name` points to
+    pub async fn get_tag(&self, name: &str) -> Option<TagVersion> {
+        let tags = self.tags.read().await;
+        tags.get(name).and_then(|versions| versions.last().cloned())
+    }
+
+    /// Every version tag `name` pointed to, oldest first
+    pub async fn tag_history(&self, name: &str) -> Option<Vec<TagVersion>> {
+        let tags = self.tags.read().await;
+        tags.get(name).cloned()
+    }
+
+    /// Every tag with its history.
+    pub async fn tag_entries(&self) -> Vec<(String, Vec<TagVersion>)> {
+        let tags = self.tags.read().await;
+        tags.iter()
+            .map(|(name, versions)| (name.clone(), versions.clone()))
+            .collect()
+    }
+
+    /// Adds the tags of another tracker, keeping the history of whichever
+    /// side published a tag last.
+    pub async fn merge_tags(&self, entries: Vec<(String, Vec<TagVersion>)>) {
+        let mut tags = self.tags.write().await;
+        for (name, versions) in entries.into_iter() {
+            let newer = |ours: &Vec<TagVersion>| {
+                let published_at = |versions: &Vec<TagVersion>| {
+                    versions.last().map(|version| version.published_at)
+                };
+                published_at(ours) < published_at(&versions)
+            };
+            if tags.get(&name).is_none_or(newer) {
+                tags.insert(name, versions);
+            }
+        }
+    }
+
+    /// Restores the peers saved by `save`. They are marked unconfirmed and
+    /// get a full timeout from now to announce again. A missing file gives an
+    /// empty state.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(err),
+        };
+        let file: StateFile = serde_binary::from_slice(&data, Endian::Little)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if file.format != STATE_FORMAT {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported tracker state format {:?}", file.format),
+            ));
+        }
+
+        let current_time = now();
+        let mut peers = Peers::default();
+        for (mut peer, md5s) in file.peers.into_iter() {
+            peer.last_seen = current_time;
+            peer.unconfirmed = true;
+            peers.insert(peer, md5s);
+        }
+        Ok(Self {
+            peers: RwLock::new(peers),
+            tags: RwLock::new(file.tags.into_iter().collect()),
+        })
+    }
+
+    /// Writes every peer and tag to a temporary file next to `path` and renames it
+    /// over `path`, so a crash never leaves a truncated state behind.
+    pub async fn save(&self, path: &Path) -> io::Result<()> {
+        let file = StateFile {
+            format: STATE_FORMAT.to_string(),
+            peers: self.entries().await,
+            tags: self.tag_entries().await,
+        };
+        let data = serde_binary::to_vec(&file, Endian::Little).map_err(io::Error::other)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        tokio::fs::write(tmp_path.as_os_str(), data).await?;
+        tokio::fs::rename(tmp_path, path).await
     }
 
     /// Remove inactive peers (older than timeout_seconds)
     pub async fn cleanup_peers(&self, timeout_seconds: u64) {
-        let current_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let current_time = now();
 
         let mut peers = self.peers.write().await;
-        peers.retain(|_, peer| current_time - peer.last_seen < timeout_seconds);
+        let expired = peers
+            .info
+            .values()
+            .filter(|peer| current_time.saturating_sub(peer.last_seen) >= timeout_seconds)
+            .map(|peer| peer.addr.clone())
+            .collect::<Vec<_>>();
+        for addr in expired.iter() {
+            peers.remove(addr);
+        }
     }
 }
 
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a peer may take to answer a health probe.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Probes `GET /health` of every peer and marks the ones that fail.
+async fn probe_peers(state: &TrackerState, client: &reqwest::Client) {
+    let addrs = state
+        .get_peers()
+        .await
+        .into_iter()
+        .map(|peer| peer.addr)
+        .collect::<Vec<_>>();
+    let results = futures::future::join_all(addrs.iter().map(|addr| async move {
+        client
+            .get(format!("{}/health", addr))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .is_ok()
+    }))
+    .await;
+    for (addr, healthy) in addrs.iter().zip(results) {
+        state.set_healthy(addr, healthy).await;
+    }
+}
+
+/// Sibling trackers that every announce is relayed to
+#[derive(Debug)]
+struct Replication {
+    siblings: Vec<String>,
+    client: reqwest::Client,
+}
+
+impl Replication {
+    /// Relays a direct announce, or with `Method::DELETE` a deregistration,
+    /// to every sibling in the background.
+    fn relay(&self, method: Method, announce_req: &AnnounceRequest) {
+        if announce_req.relayed {
+            return;
+        }
+        for sibling in self.siblings.iter() {
+            let req = self
+                .client
+                .request(method.clone(), format!("{}/announce", sibling))
+                .json(&AnnounceRequest {
+                    addr: announce_req.addr.clone(),
+                    md5s: announce_req.md5s.clone(),
+                    relayed: true,
+                });
+            let sibling = sibling.clone();
+            tokio::spawn(async move {
+                if let Err(err) = req.send().await.and_then(|r| r.error_for_status()) {
+                    eprintln!("Failed to relay announce to {}: {:?}", sibling, err);
+                }
+            });
+        }
+    }
+
+    /// Relays a tag published directly to every sibling in the background.
+    fn relay_tag(&self, name: &str, version: &TagVersion, publish_req: &PublishTagRequest) {
+        if publish_req.relayed {
+            return;
+        }
+        for sibling in self.siblings.iter() {
+            let req =
+                self.client
+                    .put(format!("{}/tags/{}", sibling, name))
+                    .json(&PublishTagRequest {
+                        md5: version.md5.clone(),
+                        published_at: Some(version.published_at),
+                        relayed: true,
+                    });
+            let sibling = sibling.clone();
+            tokio::spawn(async move {
+                if let Err(err) = req.send().await.and_then(|r| r.error_for_status()) {
+                    eprintln!("Failed to relay tag to {}: {:?}", sibling, err);
+                }
+            });
+        }
+    }
+
+    /// Merges the peers of every reachable sibling into `state`.
+    async fn catch_up(&self, state: &TrackerState) {
+        for sibling in self.siblings.iter() {
+            let result = async {
+                self.client
+                    .get(format!("{}/replica", sibling))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<ReplicaResponse>()
+                    .await
+            }
+            .await;
+            match result {
+                Ok(replica) => {
+                    state.merge(replica.peers).await;
+                    state.merge_tags(replica.tags).await;
+                }
+                Err(err) => eprintln!("Failed to catch up from {}: {:?}", sibling, err),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RouterState {
+    state: Arc<TrackerState>,
+    replication: Arc<Replication>,
+}
+
 /// HTTP tracker server
 pub struct TrackerServer {
     state: Arc<TrackerState>,
+    state_path: Option<PathBuf>,
+    siblings: Vec<String>,
+    credentials: Credentials,
+    tls: Option<ServerTls>,
+    cleanup_interval: Duration,
+    peer_timeout: Duration,
+    health_check_interval: Option<Duration>,
+}
+
+impl Default for TrackerServer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TrackerServer {
     pub fn new() -> Self {
         Self {
             state: Arc::new(TrackerState::new()),
+            state_path: None,
+            siblings: Vec::new(),
+            credentials: Credentials::default(),
+            tls: None,
+            cleanup_interval: Duration::from_secs(30),
+            peer_timeout: Duration::from_secs(300),
+            health_check_interval: None,
         }
     }
 
+    /// A tracker that restores its peers from `state_path` and saves them
+    /// there periodically, so a restart does not forget every peer.
+    pub fn with_state_path(state_path: PathBuf) -> io::Result<Self> {
+        Ok(Self {
+            state: Arc::new(TrackerState::load(state_path.as_path())?),
+            state_path: Some(state_path),
+            ..Self::new()
+        })
+    }
+
+    /// Removes peers that did not announce themselves for `peer_timeout`,
+    /// checking every `cleanup_interval`. The state file is saved as often.
+    pub fn with_timeouts(mut self, cleanup_interval: Duration, peer_timeout: Duration) -> Self {
+        self.cleanup_interval = cleanup_interval;
+        self.peer_timeout = peer_timeout;
+        self
+    }
+
+    /// Probes `GET /health` of every peer each `interval` and marks the
+    /// peers that fail as unhealthy in `/peers`.
+    pub fn with_health_check(mut self, interval: Option<Duration>) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Replicates registrations with the trackers at `siblings`: every
+    /// announce is relayed to them, and their peers are fetched on start.
+    pub fn with_siblings(mut self, siblings: Vec<String>) -> Self {
+        self.siblings = siblings;
+        self
+    }
+
+    /// Requires the token of `credentials` on every endpoint but `/`, and
+    /// sends `credentials` to the siblings and to the probed peers.
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Serves over TLS, requiring client certificates if `tls` has a client CA.
+    pub fn with_tls(mut self, tls: Option<ServerTls>) -> Self {
+        self.tls = tls;
+        self
+    }
+
     /// Build the axum router with all routes
-    fn build_router(&self) -> Router {
-        Router::new()
-            .route("/", get(handle_root))
-            .route("/announce", post(handle_announce))
+    fn build_router(&self, replication: Arc<Replication>) -> Router {
+        let router = Router::new()
+            .route("/announce", post(handle_announce).delete(handle_deregister))
             .route("/peers", get(handle_get_peers))
-            .with_state(Arc::clone(&self.state))
+            .route("/replica", get(handle_replica))
+            .route("/tags", get(handle_get_tags))
+            .route("/tags/{name}", get(handle_get_tag).put(handle_publish_tag))
+            .route("/tags/{name}/history", get(handle_tag_history));
+        auth::protect(router, self.credentials.token.clone())
+            .route("/", get(handle_root))
+            .with_state(RouterState {
+                state: Arc::clone(&self.state),
+                replication,
+            })
     }
 
     /// Start the tracker server on the specified port
     pub async fn start(&self, port: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let addr = SocketAddr::from(([0, 0, 0, 0], port));
-        let listener = TcpListener::bind(addr).await?;
+        let listener = ServerListener::bind(addr, self.tls.as_ref()).await?;
 
-        println!("Tracker HTTP server listening on http://{}", addr);
+        println!(
+            "Tracker HTTP server listening on {}://{}",
+            tls::scheme(self.tls.as_ref()),
+            addr
+        );
+        let replication = Arc::new(Replication {
+            siblings: self.siblings.clone(),
+            client: self
+                .credentials
+                .client_builder()
+                .timeout(REPLICATION_TIMEOUT)
+                .build()
+                .unwrap(),
+        });
+        // in the background, siblings may be catching up from this tracker
+        let state = Arc::clone(&self.state);
+        let catching_up = Arc::clone(&replication);
+        tokio::spawn(async move { catching_up.catch_up(&state).await });
 
         // Start cleanup task
         let state_for_cleanup = Arc::clone(&self.state);
+        let state_path = self.state_path.clone();
+        let (cleanup_interval, peer_timeout) = (self.cleanup_interval, self.peer_timeout);
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+            let mut interval = tokio::time::interval(cleanup_interval);
             loop {
                 interval.tick().await;
-                state_for_cleanup.cleanup_peers(300).await; // 5 minutes timeout
+                state_for_cleanup
+                    .cleanup_peers(peer_timeout.as_secs())
+                    .await;
+                if let Some(path) = state_path.as_ref()
+                    && let Err(err) = state_for_cleanup.save(path).await
+                {
+                    eprintln!("Failed to save tracker state: {:?}", err);
+                }
             }
         });
 
-        let app = self.build_router();
+        if let Some(health_check_interval) = self.health_check_interval {
+            let state = Arc::clone(&self.state);
+            let client = self
+                .credentials
+                .client_builder()
+                .timeout(HEALTH_TIMEOUT)
+                .build()
+                .unwrap();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(health_check_interval);
+                loop {
+                    interval.tick().await;
+                    probe_peers(&state, &client).await;
+                }
+            });
+        }
+
+        let app = self.build_router(replication);
         axum::serve(listener, app).await?;
 
         Ok(())
@@ -120,34 +693,132 @@ impl TrackerServer {
 
 /// Handle peer announcement
 async fn handle_announce(
-    State(state): State<Arc<TrackerState>>,
+    State(RouterState { state, replication }): State<RouterState>,
     Json(announce_req): Json<AnnounceRequest>,
 ) -> impl IntoResponse {
-    let current_time = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
+    replication.relay(Method::POST, &announce_req);
     let peer = PeerInfo {
         addr: announce_req.addr,
-        last_seen: current_time,
+        last_seen: now(),
+        unconfirmed: false,
+        unhealthy: false,
     };
 
-    state.announce_peer(peer).await;
+    state.announce_peer(peer, announce_req.md5s).await;
 
     ResponseJson(ApiResponse {
         status: "ok".to_string(),
     })
 }
 
-/// Handle request to get all peers
-async fn handle_get_peers(State(state): State<Arc<TrackerState>>) -> impl IntoResponse {
-    let peers = state.get_peers().await;
+/// Handle a peer that shuts down, so it is not handed out any more
+async fn handle_deregister(
+    State(RouterState { state, replication }): State<RouterState>,
+    Json(announce_req): Json<AnnounceRequest>,
+) -> impl IntoResponse {
+    replication.relay(Method::DELETE, &announce_req);
+    state.remove_peer(&announce_req.addr).await;
+
+    ResponseJson(ApiResponse {
+        status: "ok".to_string(),
+    })
+}
+
+/// Handle request to get all peers, or the peers holding `md5`
+async fn handle_get_peers(
+    State(RouterState { state, .. }): State<RouterState>,
+    Query(query): Query<PeersQuery>,
+) -> impl IntoResponse {
+    let peers = match query.md5 {
+        Some(md5) => state.get_peers_holding(md5.as_str()).await,
+        None => state.get_peers().await,
+    };
     let response = PeersResponse { peers };
 
     ResponseJson(response)
 }
 
+/// Handle request for every registration, from a sibling tracker
+async fn handle_replica(State(RouterState { state, .. }): State<RouterState>) -> impl IntoResponse {
+    ResponseJson(ReplicaResponse {
+        peers: state.entries().await,
+        tags: state.tag_entries().await,
+    })
+}
+
+/// Handle request to point a tag at a content id
+async fn handle_publish_tag(
+    State(RouterState { state, replication }): State<RouterState>,
+    UrlPath(name): UrlPath<String>,
+    Json(publish_req): Json<PublishTagRequest>,
+) -> Response {
+    if !valid_tag_name(&name) || publish_req.md5.is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let version = TagVersion {
+        md5: publish_req.md5.clone(),
+        published_at: publish_req.published_at.unwrap_or_else(now),
+    };
+    let version = state.publish_tag(&name, version).await;
+    replication.relay_tag(&name, &version, &publish_req);
+
+    ResponseJson(TagResponse {
+        name,
+        md5: version.md5,
+        published_at: version.published_at,
+    })
+    .into_response()
+}
+
+/// Handle request for the content id a tag points to
+async fn handle_get_tag(
+    State(RouterState { state, .. }): State<RouterState>,
+    UrlPath(name): UrlPath<String>,
+) -> Response {
+    match state.get_tag(&name).await {
+        Some(version) => ResponseJson(TagResponse {
+            name,
+            md5: version.md5,
+            published_at: version.published_at,
+        })
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Handle request for every tag
+async fn handle_get_tags(
+    State(RouterState { state, .. }): State<RouterState>,
+) -> impl IntoResponse {
+    let mut tags = state
+        .tag_entries()
+        .await
+        .into_iter()
+        .filter_map(|(name, mut versions)| {
+            let version = versions.pop()?;
+            Some(TagResponse {
+                name,
+                md5: version.md5,
+                published_at: version.published_at,
+            })
+        })
+        .collect::<Vec<_>>();
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+
+    ResponseJson(TagsResponse { tags })
+}
+
+/// Handle request for every content id a tag pointed to
+async fn handle_tag_history(
+    State(RouterState { state, .. }): State<RouterState>,
+    UrlPath(name): UrlPath<String>,
+) -> Response {
+    match state.tag_history(&name).await {
+        Some(versions) => ResponseJson(TagHistoryResponse { name, versions }).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
 /// Handle root path - show tracker info
 async fn handle_root() -> impl IntoResponse {
     let info = serde_json::json!({
@@ -155,9 +826,482 @@ async fn handle_root() -> impl IntoResponse {
         "version": "1.0.0",
         "endpoints": {
             "announce": "POST /announce",
-            "peers": "GET /peers"
+            "deregister": "DELETE /announce",
+            "peers": "GET /peers?md5=...",
+            "replica": "GET /replica",
+            "tags": "GET /tags",
+            "tag": "GET /tags/{name}",
+            "publish": "PUT /tags/{name}",
+            "history": "GET /tags/{name}/history"
         }
     });
 
     ResponseJson(info)
 }
+
+/// Sends `request` to each of `trackers` in turn and returns the first
+/// answer, or every error if none answered.
+async fn first_answer<T: DeserializeOwned>(
+    trackers: &[String],
+    request: impl Fn(&str) -> reqwest::RequestBuilder,
+) -> Result<T, String> {
+    let mut errs = Vec::new();
+    for tracker in trackers.iter() {
+        let result = async {
+            request(tracker)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        }
+        .await;
+        match result {
+            Ok(answer) => return Ok(answer),
+            Err(err) => errs.push(format!("{}: {}", tracker, err)),
+        }
+    }
+    if errs.is_empty() {
+        Err("no tracker given".to_string())
+    } else {
+        Err(errs.join("; "))
+    }
+}
+
+/// Resolves tag `name` on the first of `trackers` that knows it.
+pub async fn resolve_tag(
+    client: &reqwest::Client,
+    trackers: &[String],
+    name: &str,
+) -> Result<TagResponse, String> {
+    first_answer(trackers, |tracker| {
+        client.get(format!("{}/tags/{}", tracker, name))
+    })
+    .await
+    .map_err(|err| format!("failed to resolve tag {}: {}", name, err))
+}
+
+/// Every content id tag `name` pointed to, from the first of `trackers` that
+/// knows it.
+pub async fn tag_history(
+    client: &reqwest::Client,
+    trackers: &[String],
+    name: &str,
+) -> Result<TagHistoryResponse, String> {
+    first_answer(trackers, |tracker| {
+        client.get(format!("{}/tags/{}/history", tracker, name))
+    })
+    .await
+    .map_err(|err| format!("failed to get the history of tag {}: {}", name, err))
+}
+
+/// Points tag `name` at `md5` on every tracker. Fails only if no tracker
+/// took it, the others get it from their siblings.
+pub async fn publish_tag(
+    client: &reqwest::Client,
+    trackers: &[String],
+    name: &str,
+    md5: &str,
+) -> Result<TagResponse, String> {
+    if !valid_tag_name(name) {
+        return Err(format!("invalid tag name {:?}", name));
+    }
+    let req = PublishTagRequest {
+        md5: md5.to_string(),
+        published_at: None,
+        relayed: false,
+    };
+    let mut published = None;
+    let mut errs = Vec::new();
+    for tracker in trackers.iter() {
+        let result = async {
+            client
+                .put(format!("{}/tags/{}", tracker, name))
+                .json(&req)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<TagResponse>()
+                .await
+        }
+        .await;
+        match result {
+            Ok(tag) => published = Some(tag),
+            Err(err) => errs.push(format!("{}: {}", tracker, err)),
+        }
+    }
+    match published {
+        Some(tag) => {
+            for err in errs.iter() {
+                eprintln!("Failed to publish tag {} to {}", name, err);
+            }
+            Ok(tag)
+        }
+        None if errs.is_empty() => Err("no tracker given".to_string()),
+        None => Err(format!(
+            "failed to publish tag {}: {}",
+            name,
+            errs.join("; ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn peer(addr: &str) -> PeerInfo {
+        PeerInfo {
+            addr: addr.to_string(),
+            last_seen: 0,
+            unconfirmed: false,
+            unhealthy: false,
+        }
+    }
+
+    fn addrs(mut peers: Vec<PeerInfo>) -> Vec<String> {
+        peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        peers.into_iter().map(|peer| peer.addr).collect()
+    }
+
+    #[tokio::test]
+    async fn test_peers_holding() {
+        let state = TrackerState::new();
+        state
+            .announce_peer(peer("a"), Some(vec!["x".to_string(), "y".to_string()]))
+            .await;
+        state
+            .announce_peer(peer("b"), Some(vec!["y".to_string()]))
+            .await;
+        // a peer that announces no ids may hold anything
+        state.announce_peer(peer("c"), None).await;
+
+        assert_eq!(addrs(state.get_peers_holding("x").await), vec!["a", "c"]);
+        assert_eq!(
+            addrs(state.get_peers_holding("y").await),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(addrs(state.get_peers_holding("z").await), vec!["c"]);
+
+        // a new announce replaces the ids of the peer
+        state
+            .announce_peer(peer("a"), Some(vec!["z".to_string()]))
+            .await;
+        assert_eq!(addrs(state.get_peers_holding("x").await), vec!["c"]);
+        assert_eq!(addrs(state.get_peers_holding("z").await), vec!["a", "c"]);
+
+        state.cleanup_peers(0).await;
+        assert!(state.get_peers().await.is_empty());
+        assert!(state.peers.read().await.holders.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge_clock_ahead() {
+        let state = TrackerState::new();
+        let ahead = |addr: &str| PeerInfo {
+            last_seen: now() + 3600,
+            ..peer(addr)
+        };
+        // a sibling whose clock is ahead does not make a peer live longer
+        state.merge(vec![(ahead("a"), None)]).await;
+        assert!(state.get_peers().await[0].last_seen <= now());
+        // nor does a time ahead break the cleanup
+        state.peers.write().await.insert(ahead("b"), None);
+        state.cleanup_peers(300).await;
+        assert_eq!(addrs(state.get_peers().await), vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_publish_tag_order() {
+        let state = TrackerState::new();
+        let version = |md5: &str, published_at: u64| TagVersion {
+            md5: md5.to_string(),
+            published_at,
+        };
+        state.publish_tag("app:latest", version("v1", 10)).await;
+        state.publish_tag("app:latest", version("v3", 30)).await;
+
+        // a version relayed late goes into the history, the tag stays
+        let current = state.publish_tag("app:latest", version("v2", 20)).await;
+        assert_eq!(current, version("v3", 30));
+        // and relaying it again changes nothing
+        state.publish_tag("app:latest", version("v2", 20)).await;
+        assert_eq!(
+            state.tag_history("app:latest").await.unwrap(),
+            vec![version("v1", 10), version("v2", 20), version("v3", 30)]
+        );
+        assert_eq!(
+            state.get_tag("app:latest").await.unwrap(),
+            version("v3", 30)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("tracker.state");
+        assert!(
+            TrackerState::load(path.as_path())?
+                .get_peers()
+                .await
+                .is_empty()
+        );
+
+        let state = TrackerState::new();
+        state
+            .announce_peer(peer("a"), Some(vec!["x".to_string()]))
+            .await;
+        state.announce_peer(peer("b"), None).await;
+        let version = |md5: &str| TagVersion {
+            md5: md5.to_string(),
+            published_at: 1,
+        };
+        state.publish_tag("app:latest", version("v1")).await;
+        state.publish_tag("app:latest", version("v2")).await;
+        state.save(path.as_path()).await?;
+
+        let state = TrackerState::load(path.as_path())?;
+        let peers = state.get_peers().await;
+        assert!(
+            peers
+                .iter()
+                .all(|peer| peer.unconfirmed && peer.last_seen > 0)
+        );
+        assert_eq!(addrs(peers), vec!["a", "b"]);
+        assert_eq!(addrs(state.get_peers_holding("y").await), vec!["b"]);
+        assert_eq!(
+            state.tag_history("app:latest").await,
+            Some(vec![version("v1"), version("v2")])
+        );
+
+        // a fresh announce confirms the peer again
+        state.announce_peer(peer("a"), None).await;
+        let peers = state.get_peers().await;
+        assert!(
+            peers
+                .iter()
+                .any(|peer| peer.addr == "a" && !peer.unconfirmed)
+        );
+        Ok(())
+    }
+
+    async fn peers_of(tracker: &str, md5: &str) -> Vec<String> {
+        let url = format!("{}/peers?md5={}", tracker, md5);
+        let resp = reqwest::get(url).await.unwrap();
+        addrs(resp.json::<PeersResponse>().await.unwrap().peers)
+    }
+
+    async fn announce(tracker: &str, addr: &str, md5: &str) {
+        reqwest::Client::new()
+            .post(format!("{}/announce", tracker))
+            .json(&AnnounceRequest {
+                addr: addr.to_string(),
+                md5s: Some(vec![md5.to_string()]),
+                relayed: false,
+            })
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+
+    async fn deregister(tracker: &str, addr: &str) {
+        reqwest::Client::new()
+            .delete(format!("{}/announce", tracker))
+            .json(&AnnounceRequest {
+                addr: addr.to_string(),
+                md5s: None,
+                relayed: false,
+            })
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replication() {
+        let ports = [19180, 19181, 19182];
+        let urls = ports
+            .iter()
+            .map(|port| format!("http://127.0.0.1:{}", port))
+            .collect::<Vec<_>>();
+        let start = |i: usize| {
+            let siblings = urls
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, url)| url.clone())
+                .collect();
+            let port = ports[i];
+            tokio::spawn(async move {
+                let server = TrackerServer::new().with_siblings(siblings);
+                server.start(port).await.unwrap();
+            })
+        };
+        let trackers = (0..3).map(start).collect::<Vec<_>>();
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+
+        // an announce to one tracker reaches every tracker
+        announce(&urls[0], "http://peer-a", "x").await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        for url in urls.iter() {
+            assert_eq!(peers_of(url, "x").await, vec!["http://peer-a"]);
+        }
+
+        // the others keep answering when one tracker dies
+        trackers[0].abort();
+        announce(&urls[1], "http://peer-b", "x").await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        assert!(reqwest::get(urls[0].as_str()).await.is_err());
+        for url in urls[1..].iter() {
+            assert_eq!(
+                peers_of(url, "x").await,
+                vec!["http://peer-a", "http://peer-b"]
+            );
+        }
+
+        // a restarted tracker catches up from its siblings
+        let _restarted = start(0);
+        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+        assert_eq!(
+            peers_of(&urls[0], "x").await,
+            vec!["http://peer-a", "http://peer-b"]
+        );
+
+        // a deregistered peer is gone from every tracker right away
+        deregister(&urls[2], "http://peer-a").await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        for url in urls.iter() {
+            assert_eq!(peers_of(url, "x").await, vec!["http://peer-b"]);
+        }
+
+        // a tag published to one tracker can be resolved on every tracker
+        let client = reqwest::Client::new();
+        publish_tag(&client, &urls[1..2], "app:latest", "x")
+            .await
+            .unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        for url in urls.iter() {
+            let tag = resolve_tag(&client, std::slice::from_ref(url), "app:latest")
+                .await
+                .unwrap();
+            assert_eq!(tag.md5, "x");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tags() {
+        let tracker = "http://127.0.0.1:19184".to_string();
+        tokio::spawn(async {
+            TrackerServer::new().start(19184).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let client = reqwest::Client::new();
+        let trackers = [tracker.clone()];
+
+        assert!(resolve_tag(&client, &trackers, "app:latest").await.is_err());
+        let v1 = publish_tag(&client, &trackers, "app:latest", "v1")
+            .await
+            .unwrap();
+        assert_eq!(v1.md5, "v1");
+
+        // the tag moves to a new version, republishing it changes nothing
+        publish_tag(&client, &trackers, "app:latest", "v2")
+            .await
+            .unwrap();
+        publish_tag(&client, &trackers, "app:latest", "v2")
+            .await
+            .unwrap();
+        publish_tag(&client, &trackers, "app:1.0", "v1")
+            .await
+            .unwrap();
+        let tag = resolve_tag(&client, &trackers, "app:latest").await.unwrap();
+        assert_eq!(tag.md5, "v2");
+        let history = tag_history(&client, &trackers, "app:latest").await.unwrap();
+        let md5s = history
+            .versions
+            .iter()
+            .map(|version| version.md5.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(md5s, vec!["v1", "v2"]);
+
+        let tags = reqwest::get(format!("{}/tags", tracker))
+            .await
+            .unwrap()
+            .json::<TagsResponse>()
+            .await
+            .unwrap();
+        let names = tags
+            .tags
+            .iter()
+            .map(|tag| (tag.name.as_str(), tag.md5.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![("app:1.0", "v1"), ("app:latest", "v2")]);
+
+        for name in ["", "a/b", "a b", "a%2Fb"] {
+            assert!(!valid_tag_name(name), "{:?}", name);
+        }
+        let status = client
+            .put(format!("{}/tags/a%20b", tracker))
+            .json(&PublishTagRequest {
+                md5: "v1".to_string(),
+                published_at: None,
+                relayed: false,
+            })
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_and_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let healthy = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route("/health", get(|| async { "ok" }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let tracker = "http://127.0.0.1:19183";
+        tokio::spawn(async {
+            let server = TrackerServer::new()
+                .with_timeouts(Duration::from_millis(100), Duration::from_secs(1))
+                .with_health_check(Some(Duration::from_millis(100)));
+            server.start(19183).await.unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // a peer that announces but does not answer probes is marked by one
+        // of the next probes
+        announce(tracker, &healthy, "x").await;
+        let mut unhealthy = Vec::new();
+        for _ in 0..20 {
+            announce(tracker, "http://127.0.0.1:1", "x").await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let resp = reqwest::get(format!("{}/peers?md5=x", tracker))
+                .await
+                .unwrap()
+                .json::<PeersResponse>()
+                .await
+                .unwrap();
+            unhealthy = resp
+                .peers
+                .into_iter()
+                .filter(|peer| peer.unhealthy)
+                .map(|peer| peer.addr)
+                .collect::<Vec<_>>();
+            if !unhealthy.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(unhealthy, vec!["http://127.0.0.1:1"]);
+
+        // peers that stop announcing are gone after the configured timeout
+        tokio::time::sleep(Duration::from_millis(2200)).await;
+        assert!(peers_of(tracker, "x").await.is_empty());
+    }
+}
@@ -212,7 +212,7 @@ mod tests {
 
         assert_eq!(handle_int.await.unwrap(), 42);
         assert_eq!(handle_string.await.unwrap(), "hello");
-        assert_eq!(handle_bool.await.unwrap(), true);
+        assert!(handle_bool.await.unwrap());
     }
 
     #[tokio::test]